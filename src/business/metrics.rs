@@ -0,0 +1,72 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    updates: AtomicU64,
+    poll_errors: AtomicU64,
+    flagged: AtomicU64,
+    target_known: AtomicBool,
+}
+
+impl Metrics {
+    pub fn update(&self) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn poll_error(&self) {
+        self.poll_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_flagged(&self, count: usize) {
+        self.flagged.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_target_known(&self, known: bool) {
+        self.target_known.store(known, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let metrics = [
+            ("lsf_updates_total", "counter", "hosts files written", self.updates.load(Ordering::Relaxed)),
+            ("lsf_poll_errors_total", "counter", "failed polls of the docker daemon", self.poll_errors.load(Ordering::Relaxed)),
+            ("lsf_flagged_containers", "gauge", "containers carrying the label", self.flagged.load(Ordering::Relaxed)),
+            ("lsf_target_known", "gauge", "whether a target ip is known", u64::from(self.target_known.load(Ordering::Relaxed))),
+        ];
+
+        for (name, kind, help, value) in metrics {
+            let _ = write!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+        }
+
+        out
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub async fn serve(addr: String, metrics: std::sync::Arc<Metrics>) -> super::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = [0; 1024];
+        let read = stream.read(&mut request).await.unwrap_or(0);
+        let request = String::from_utf8_lossy(&request[..read]);
+
+        let response = if request.starts_with("GET /metrics ") {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        };
+
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
@@ -1,17 +1,128 @@
+mod metrics;
+
 use async_trait::async_trait;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::fmt::{Debug, Display, Formatter};
-use std::io::Write;
-use std::time::{Duration, Instant};
+use futures_util::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::fmt::{Arguments, Debug, Display, Formatter};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+pub use metrics::Metrics;
+
+pub const SERVICE_LABEL: &str = "com.docker.compose.service";
+pub const PROJECT_LABEL: &str = "com.docker.compose.project";
+/// Names a container under something else than its service, see [`Container::alias`].
+pub const ALIAS_LABEL: &str = "lsf.alias";
+/// As `network`, stands for the network of the first target found.
+pub const AUTO_NETWORK: &str = "auto";
+
+/// Every error the crate reports, keeping its kind so that retries and reconnections can match on it.
+/// Tar archives report their failures as io errors.
+#[derive(Debug)]
+pub enum BusinessError {
+    Io(std::io::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Bollard(bollard::errors::Error),
+    Docker(DockerError),
+    Config(ConfigError),
+    Stack(StackError),
+    /// Anything else, described by its message.
+    Other(String),
+}
+
+impl Display for BusinessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusinessError::Io(e) => write!(f, "{}", e),
+            BusinessError::Toml(e) => write!(f, "{}", e),
+            BusinessError::Json(e) => write!(f, "{}", e),
+            BusinessError::Bollard(e) => write!(f, "{}", e),
+            BusinessError::Docker(e) => write!(f, "{}", e),
+            BusinessError::Config(e) => write!(f, "{}", e),
+            BusinessError::Stack(e) => write!(f, "{}", e),
+            BusinessError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for BusinessError {}
+
+impl From<std::io::Error> for BusinessError {
+    fn from(error: std::io::Error) -> Self {
+        BusinessError::Io(error)
+    }
+}
+
+impl From<toml::de::Error> for BusinessError {
+    fn from(error: toml::de::Error) -> Self {
+        BusinessError::Toml(error)
+    }
+}
+
+impl From<serde_json::Error> for BusinessError {
+    fn from(error: serde_json::Error) -> Self {
+        BusinessError::Json(error)
+    }
+}
+
+impl From<bollard::errors::Error> for BusinessError {
+    fn from(error: bollard::errors::Error) -> Self {
+        BusinessError::Bollard(error)
+    }
+}
+
+impl From<DockerError> for BusinessError {
+    fn from(error: DockerError) -> Self {
+        BusinessError::Docker(error)
+    }
+}
+
+impl From<ConfigError> for BusinessError {
+    fn from(error: ConfigError) -> Self {
+        BusinessError::Config(error)
+    }
+}
+
+impl From<StackError> for BusinessError {
+    fn from(error: StackError) -> Self {
+        BusinessError::Stack(error)
+    }
+}
+
+impl From<String> for BusinessError {
+    fn from(message: String) -> Self {
+        BusinessError::Other(message)
+    }
+}
 
-type Error = Box<dyn std::error::Error>;
+impl From<&str> for BusinessError {
+    fn from(message: &str) -> Self {
+        BusinessError::Other(message.to_string())
+    }
+}
+
+pub type Error = BusinessError;
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum DockerError {
     NoName(String),
     NoHost(String),
+    NoCertPath,
+    MissingCertificate(String),
+    Connection(String),
+    Transient(String),
+    NoSuchContainer(String),
+    ExecFailed(String, String),
+    ExecTimeout(String),
 }
 
 impl Display for DockerError {
@@ -19,323 +130,2777 @@ impl Display for DockerError {
         match self {
             DockerError::NoName(id) => write!(f, "container {} has no name, cannot fetch from it", id),
             DockerError::NoHost(id) => write!(f, "container {} has no /etc/hosts file", id),
+            DockerError::NoCertPath => write!(f, "DOCKER_TLS_VERIFY is set but DOCKER_CERT_PATH is not"),
+            DockerError::MissingCertificate(path) => write!(f, "tls certificate {} is missing", path),
+            DockerError::Connection(e) => write!(f, "lost connection to docker daemon: {}", e),
+            DockerError::Transient(e) => write!(f, "docker daemon failed to answer: {}", e),
+            DockerError::NoSuchContainer(e) => write!(f, "no such container: {}", e),
+            DockerError::ExecFailed(id, stderr) => write!(f, "writing /etc/hosts in container {} failed: {}", id, stderr),
+            DockerError::ExecTimeout(id) => write!(f, "writing /etc/hosts in container {} timed out", id),
         }
     }
 }
 
 impl std::error::Error for DockerError {}
 
-#[derive(Deserialize)]
-pub struct Config {
-    pub(crate) network: String,
-    pub(crate) label_key: String,
-    pub(crate) target: String,
-    pub(crate) dependencies: Vec<String>,
+#[derive(Debug)]
+pub enum ConfigError {
+    Empty(&'static str),
+    Pattern(String),
+    Relative(&'static str),
+    Hostnames(Vec<String>),
+    UndefinedVariable(String),
 }
 
-#[async_trait]
-pub trait Docker {
-    async fn poll(&mut self) -> Result<HashMap<String, RawContainer>>;
-    async fn update_hosts_for(&self, container: Container, dependencies: &[String], network: &str, target: &str, host: &str) -> Result<()>;
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Empty(field) => write!(f, "config field {} must not be empty", field),
+            ConfigError::Pattern(e) => write!(f, "config field target is not a valid pattern: {}", e),
+            ConfigError::Relative(field) => write!(f, "config field {} must be an absolute path", field),
+            ConfigError::Hostnames(names) => write!(f, "config field dependencies holds invalid hostnames: {}", names.join(", ")),
+            ConfigError::UndefinedVariable(name) => write!(f, "config references undefined environment variable {}", name),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct StringVec {
-    inner: Vec<String>,
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug)]
+pub enum StackError {
+    NoTarget(String),
 }
 
-impl Display for StringVec {
+impl Display for StackError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[")?;
-        write!(f, "{}", self.inner.join(", "))?;
-        write!(f, "]")
+        match self {
+            StackError::NoTarget(targets) => write!(f, "no target «{}» found in a single poll", targets),
+        }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct RawContainer {
-    pub id: String,
-    pub name: Option<String>,
-    pub networks: HashMap<String, String>,
-    pub labels: HashMap<String, String>,
+impl std::error::Error for StackError {}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Config {
+    #[serde(deserialize_with = "one_or_many")]
+    pub network: Vec<String>,
+    pub project: Option<String>,
+    pub label_key: String,
+    pub label_value: Option<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub target: Vec<String>,
+    #[serde(default)]
+    pub dependencies: StringVec,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub poll_interval_ms: Option<u64>,
+    pub docker_socket: Option<String>,
+    pub retry_base_delay_ms: Option<u64>,
+    pub exec_timeout_ms: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub max_poll_errors: Option<u32>,
+    pub max_concurrent_updates: Option<usize>,
+    #[serde(default)]
+    pub cleanup_on_gone: bool,
+    #[serde(default)]
+    pub cleanup_on_lost_network: bool,
+    #[serde(default)]
+    pub cleanup_on_target_gone: bool,
+    pub guard_prefix: Option<String>,
+    pub hosts_path: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub inspect_missing_ip: bool,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub timestamps: Option<bool>,
+    pub metrics_addr: Option<String>,
+    #[serde(default)]
+    pub oneshot: bool,
+    #[serde(default)]
+    pub target_is_regex: bool,
+    #[serde(default)]
+    pub target_match: TargetMatch,
+    #[serde(default)]
+    pub dependencies_from_labels: bool,
+    pub dependencies_label: Option<String>,
+    pub dependency_suffix: Option<String>,
+    pub target_alias: Option<String>,
+    pub events: Option<bool>,
+    #[serde(default)]
+    pub verbosity: Verbosity,
+    #[serde(default)]
+    pub summary: bool,
+    pub state_file: Option<String>,
+    /// Keeps the known targets across restarts, so flagged containers started meanwhile are routed right away.
+    pub target_file: Option<String>,
+    /// Start over from the new config when it changes, clearing what the previous one wrote, rather than swapping it in place.
+    #[serde(default)]
+    pub restart_on_change: bool,
+    #[serde(default)]
+    pub annotate: bool,
+    pub log_file: Option<String>,
+    pub log_ignored: Option<bool>,
+    pub require_flag: Option<bool>,
+    /// Polls finding more flagged and target containers than this are skipped, against a label selector matching too much.
+    pub max_containers: Option<usize>,
+    #[serde(default)]
+    pub syslog: bool,
+    pub health_file: Option<String>,
+    pub service_label_key: Option<String>,
+    #[serde(default)]
+    pub reconcile_on_start: bool,
+    /// Also writes in each target a block resolving the flagged containers routed to it, by service name or `lsf.alias` label.
+    #[serde(default)]
+    pub route_into_target: bool,
+    /// How many polls in a row a new container must be seen the same way before being taken in, 0 and 1 taking it in right away.
+    #[serde(default)]
+    pub debounce_polls: u32,
+    /// Also routes every flagged container to the other flagged containers of its network, by service name or `lsf.alias` label.
+    #[serde(default)]
+    pub mesh: bool,
+    /// Written as is in every guard block, sorted so that blocks stay comparable. Last, as toml needs tables after plain values.
+    #[serde(default)]
+    pub extra_hosts: BTreeMap<String, String>,
 }
 
-#[derive(Clone)]
-pub struct Container {
-    id: String,
-    name: Option<String>,
-    service: Option<String>,
-    ip: Option<String>,
-    flag: Option<String>,
+/// What `target` is compared with: `"service"`, `"name"` or `"label:key"`, also read as `{ label = "key" }`.
+/// Serialized as a string, since toml cannot write an enum variant holding a value.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TargetMatch {
+    /// The service label, see `service_label_key`.
+    #[default]
+    Service,
+    /// The container name.
+    Name,
+    /// The value of the given label.
+    Label(String),
 }
 
-impl Display for Container {
+impl Display for TargetMatch {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "container {}", self.hash())?;
-
-        if self.flag.is_some() {
-            write!(f, " flagged")?;
-        } else {
-            write!(f, " unflagged")?;
+        match self {
+            TargetMatch::Service => write!(f, "service"),
+            TargetMatch::Name => write!(f, "name"),
+            TargetMatch::Label(key) => write!(f, "label:{}", key),
         }
+    }
+}
 
-        if let Some(service) = &self.service {
-            write!(f, " service {}", service)?;
-        }
+impl Serialize for TargetMatch {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-        if let Some(name) = &self.name {
-            write!(f, " named {}", name)?;
-        } else {
-            write!(f, " unnamed")?;
+impl<'de> Deserialize<'de> for TargetMatch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Form {
+            Plain(String),
+            Label { label: String },
         }
 
-        if let Some(ip) = &self.ip {
-            write!(f, " in network at ip {}", ip)?;
-        } else {
-            write!(f, " orphan")?;
+        match Form::deserialize(deserializer)? {
+            Form::Plain(value) if value == "service" => Ok(TargetMatch::Service),
+            Form::Plain(value) if value == "name" => Ok(TargetMatch::Name),
+            Form::Plain(value) => match value.strip_prefix("label:") {
+                Some(key) if !key.is_empty() => Ok(TargetMatch::Label(key.to_string())),
+                _ => Err(serde::de::Error::custom(format!("unknown target_match {}, expected service, name or label:<key>", value))),
+            },
+            Form::Label { label } => Ok(TargetMatch::Label(label)),
         }
-
-        Ok(())
     }
 }
 
-impl Container {
-    pub fn id(&self) -> String {
-        self.id.clone()
-    }
+/// A docker listing filter, `key` or `key=value` for labels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PollFilter {
+    Label(String),
+    Name(String),
+    Network(String),
+}
 
-    pub fn name(&self) -> Option<String> {
-        self.name.clone()
+impl PollFilter {
+    /// The docker filter name.
+    #[must_use]
+    pub fn key(&self) -> &'static str {
+        match self {
+            PollFilter::Label(_) => "label",
+            PollFilter::Name(_) => "name",
+            PollFilter::Network(_) => "network",
+        }
     }
 
-    pub fn hash(&self) -> &str {
-        &self.id[0..16]
+    #[must_use]
+    pub fn value(&self) -> &str {
+        match self {
+            PollFilter::Label(value) | PollFilter::Name(value) | PollFilter::Network(value) => value,
+        }
     }
 }
 
-enum StackEvents {
-    New(Container),
-    Target(Container, Vec<Container>, String),
-    Gone(Container),
-    NoFlag(Container),
-    OutsideNetwork(Container),
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
 }
 
-struct CurrentStack {
-    config: Config,
-    target_ip: Option<String>,
-    map: Option<HashMap<String, Container>>,
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// Errors, warnings and target changes only.
+    Quiet,
+    #[default]
+    Normal,
+    /// Everything, the per-poll lines included.
+    Verbose,
 }
 
-impl CurrentStack {
-    async fn loop_once<D: Docker, W: Write>(&mut self, docker: &mut D, f: &mut W) -> Result<()> {
-        let containers = docker.poll().await?;
-        let events = self.actualize(containers);
-
-        for event in events {
-            match event {
-                StackEvents::Target(container, known, ip) => {
-                    writeln!(f, "event found target: {} applying it to known {} containers", container, known.len())?;
-                    for item in known {
-                        writeln!(f, "updating previous container {}", item.hash())?;
-                        docker.update_hosts_for(item, &self.config.dependencies, &self.config.network, &self.config.network, &ip).await?;
-                    }
-                    writeln!(f, "recording ip for target: {}", ip)?;
-                    self.target_ip = Some(ip);
-                }
-                StackEvents::New(container) => {
-                    writeln!(f, "event container match: {}", container)?;
-                    if let Some(ip) = &self.target_ip {
-                        writeln!(f, "updating /etc/hosts for container {}", container.hash())?;
-                        docker.update_hosts_for(container, &self.config.dependencies, &self.config.network, &self.config.target, ip).await?;
-                    } else {
-                        writeln!(f, "could not update /etc/hosts for container {} because no target known yet", container.hash())?;
-                    }
-                }
-                StackEvents::Gone(container) => {
-                    writeln!(f, "event container gone: {}", container)?;
-                }
-                StackEvents::NoFlag(container) => {
-                    writeln!(f, "event container ignored (label): {}", container, )?;
-                }
-                StackEvents::OutsideNetwork(container) => {
-                    writeln!(f, "event container ignored (network): {}", container, )?;
-                }
-            }
+impl Verbosity {
+    #[must_use]
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "quiet" => Some(Verbosity::Quiet),
+            "normal" => Some(Verbosity::Normal),
+            "verbose" => Some(Verbosity::Verbose),
+            _ => None,
         }
+    }
 
-        Ok(())
+    fn shows(self, level: Level) -> bool {
+        match self {
+            Verbosity::Quiet => level >= Level::Notice,
+            Verbosity::Normal => level >= Level::Info,
+            Verbosity::Verbose => true,
+        }
     }
 }
 
-impl CurrentStack {
-    fn new(config: Config) -> Self {
-        Self {
-            config,
-            map: Some(HashMap::default()),
-            target_ip: None,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Notice,
+    Warn,
+    Error,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Debug => write!(f, "DEBUG"),
+            Level::Info => write!(f, "INFO"),
+            Level::Notice => write!(f, "NOTICE"),
+            Level::Warn => write!(f, "WARN"),
+            Level::Error => write!(f, "ERROR"),
         }
     }
 }
 
-impl CurrentStack {
-    fn actualize(&mut self, mut raw_containers: HashMap<String, RawContainer>) -> Vec<StackEvents> {
-        let mut events = vec![];
-        events.reserve(raw_containers.len());
+/// Where the logs go, told the level of each line before it is written,
+/// so that a writer keeping levels apart, as syslog does, never reads them back from the line.
+pub trait LogWrite: Write {
+    fn level(&mut self, _level: Level) {}
+}
 
-        let known_containers = self.map.take().expect("start");
-        let mut new_containers = HashMap::default();
+impl LogWrite for Vec<u8> {}
+impl LogWrite for std::io::Stdout {}
+impl LogWrite for std::io::Sink {}
+impl LogWrite for std::fs::File {}
 
-        for (id, container) in known_containers {
-            if raw_containers.contains_key(&id) {
-                raw_containers.remove(&id);
-                new_containers.insert(id.clone(), container);
-            } else {
-                events.push(StackEvents::Gone(container));
-            }
-        }
+impl<W: LogWrite + ?Sized> LogWrite for &mut W {
+    fn level(&mut self, level: Level) {
+        (**self).level(level);
+    }
+}
 
-        for (id, new) in raw_containers {
-            let ip = new.networks.get(&self.config.network);
-            let service = new.labels.get("com.docker.compose.service").cloned();
+impl<W: LogWrite + ?Sized> LogWrite for Box<W> {
+    fn level(&mut self, level: Level) {
+        (**self).level(level);
+    }
+}
 
-            let flag = new.labels.get(&self.config.label_key);
+/// Logs to any writer, levels going unseen but in the lines, see [`event_loop`].
+struct Unleveled<W>(W);
 
-            let c = Container {
-                id: id.clone(),
-                name: new.name.clone(),
-                service: service.clone(),
-                ip: ip.cloned(),
-                flag: flag.cloned(),
-            };
+impl<W: Write> Write for Unleveled<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
 
-            let container = c.clone();
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
 
-            if ip.is_some() && Some(&self.config.target) == service.as_ref() {
-                events.push(StackEvents::Target(container, new_containers.values().filter(|item| {
-                    item.flag.is_some() && item.ip.is_some()
-                }).cloned().collect(), ip.cloned().unwrap()));
-            } else if ip.is_some() && flag.is_some() {
-                events.push(StackEvents::New(container));
-            } else if ip.is_some() {
-                events.push(StackEvents::NoFlag(container));
-            } else {
-                events.push(StackEvents::OutsideNetwork(container));
-            }
+impl<W: Write> LogWrite for Unleveled<W> {}
 
-            new_containers.insert(id.clone(), c);
-        }
+fn log_line<W: LogWrite>(f: &mut W, config: &Config, level: Level, line: Arguments) -> Result<()> {
+    f.level(level);
+    if config.timestamps() {
+        write!(f, "{} ", humantime::format_rfc3339_seconds(SystemTime::now()))?;
+    }
+    writeln!(f, "{} {}", level, line)?;
+    Ok(())
+}
 
-        self.map = Some(new_containers);
+fn text<W: LogWrite>(f: &mut W, config: &Config, level: Level, line: Arguments) -> Result<()> {
+    if config.log_format == LogFormat::Text && config.verbosity.shows(level) {
+        log_line(f, config, level, line)?;
+    }
+    Ok(())
+}
 
-        events
+fn json_line<W: LogWrite>(f: &mut W, config: &Config, level: Level, mut event: Value) -> Result<()> {
+    f.level(level);
+    event["level"] = json!(level.to_string());
+    if config.timestamps() {
+        event["timestamp"] = json!(humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
     }
+    writeln!(f, "{}", event)?;
+    Ok(())
 }
 
-pub async fn event_loop<D: Docker, W: Write>(
-    mut docker: D,
-    mut write: W,
-    config: Config,
-) -> Result<()> {
-    let tick_rate = Duration::from_secs(1);
-    let mut last_tick = Instant::now();
-    let mut stack = CurrentStack::new(config);
-    writeln!(
-        write,
-        "Looking for containers in network {} with label {} to be routed via service «{}»",
-        stack.config.network, stack.config.label_key, stack.config.target
-    )?;
-    loop {
-        stack.loop_once(&mut docker, &mut write).await?;
-
-        if tick_rate > last_tick.elapsed() {
-            std::thread::sleep(
-                tick_rate
-                    .checked_sub(last_tick.elapsed())
-                    .unwrap_or(Duration::from_millis(0)),
-            );
-            last_tick = Instant::now();
-        }
+fn json<W: LogWrite>(f: &mut W, config: &Config, level: Level, event: Value) -> Result<()> {
+    if config.log_format == LogFormat::Json && config.verbosity.shows(level) {
+        json_line(f, config, level, event)?;
     }
+    Ok(())
 }
 
-pub fn update_host_file(file: String, lines: &[String], network: &str, target: &str, host: &str) -> String {
-    const PACKAGE: &str = env!("CARGO_PKG_NAME");
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
 
-    let open_guard = format!("### open {} {} {}\n", PACKAGE,network, target);
-    let close_guard = format!("### close {} {} {}\n", PACKAGE, network, target);
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
 
-    let content = trim_host_from_guards(file, &open_guard, &close_guard);
+impl Config {
+    const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+    const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 100;
+    const DEFAULT_MAX_RETRIES: u32 = 3;
+    const DEFAULT_MAX_POLL_ERRORS: u32 = 3;
+    const DEFAULT_MAX_CONCURRENT_UPDATES: usize = 8;
+    const DEFAULT_EXEC_TIMEOUT_MS: u64 = 10_000;
+    const DEFAULT_GUARD_PREFIX: &'static str = "###";
+    const DEFAULT_HOSTS_PATH: &'static str = "/etc/hosts";
+    const DEFAULT_DEPENDENCIES_LABEL: &'static str = "com.docker.compose.depends_on";
 
-    format!(
-        "{}{open_guard}{}{close_guard}",
-        content,
-        lines.iter().map(|str| format!("{}\t{}\n", host, str)).collect::<Vec<String>>().join("")
-    )
-}
+    /// Replaces `${VAR}` in `network`, `label_key`, `target` and `dependencies` with the value of the environment variable.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConfigError::UndefinedVariable`] when a referenced variable is not set.
+    pub fn expand_env(&mut self) -> Result<()> {
+        for value in self.network.iter_mut().chain(std::iter::once(&mut self.label_key)).chain(&mut self.target).chain(&mut self.dependencies.inner) {
+            *value = expand_env(value)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the config before the loop starts.
+    ///
+    /// # Errors
+    ///
+    /// Fails with the [`ConfigError`] of the first field that is empty, relative, or not a valid pattern or hostname.
+    pub fn validate(&self) -> Result<()> {
+        fn is_empty(values: &[String]) -> bool {
+            values.is_empty() || values.iter().any(String::is_empty)
+        }
 
-fn trim_host_from_guards(file: String, open_guard: &str, close_guard: &str) -> String {
-    let mut content = String::new();
+        if is_empty(&self.network) {
+            return Err(ConfigError::Empty("network").into());
+        }
+        if is_empty(&self.target) {
+            return Err(ConfigError::Empty("target").into());
+        }
+        if self.label_key.is_empty() {
+            return Err(ConfigError::Empty("label_key").into());
+        }
+        self.targets()?;
+        if !Path::new(self.hosts_path()).is_absolute() {
+            return Err(ConfigError::Relative("hosts_path").into());
+        }
+        let invalid: Vec<String> = self.dependencies.as_slice().iter().filter(|name| !is_hostname(name)).cloned().collect();
+        if !invalid.is_empty() {
+            return Err(ConfigError::Hostnames(invalid).into());
+        }
 
-    if let Some(hosts) = file.split(open_guard).next() {
-        content.push_str(hosts);
+        Ok(())
     }
-    if let Some(hosts) = file.split(close_guard).nth(1) {
-        content.push_str(hosts);
+
+    /// What `validate` lets through though it is likely a mistake, logged once the loop starts.
+    #[must_use]
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.dependencies.is_empty() && !self.dependencies_from_labels {
+            warnings.push("config field dependencies is empty, no host will be routed".to_string());
+        }
+        if self.syslog && !cfg!(feature = "syslog") {
+            let fallback = self.log_file.as_deref().unwrap_or("stdout");
+            warnings.push(format!("syslog is set but {} was built without the syslog feature, logging to {}", env!("CARGO_PKG_NAME"), fallback));
+        }
+        warnings
     }
-    content
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::business::trim_host_from_guards;
+    #[must_use]
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms.unwrap_or(Self::DEFAULT_POLL_INTERVAL_MS))
+    }
 
-    const PACKAGE: &str = env!("CARGO_PKG_NAME");
+    #[must_use]
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_ms.unwrap_or(Self::DEFAULT_RETRY_BASE_DELAY_MS))
+    }
 
-    #[test]
-    pub fn test_remove() {
-        let host_file = format!("
-1.1.1.1 toto
-### open guard guard
-### close guard guard
-1.2.3.4 titi
-");
+    /// How many failed polls in a row are tolerated before giving up.
+    #[must_use]
+    pub fn max_poll_errors(&self) -> u32 {
+        self.max_poll_errors.unwrap_or(Self::DEFAULT_MAX_POLL_ERRORS)
+    }
 
-        let str = "
-1.1.1.1 toto
-1.2.3.4 titi
-".to_string();
-        assert_eq!(
-            trim_host_from_guards(host_file, "### open guard guard\n", "### close guard guard\n"),
-            str
-        )
+    /// How many hosts files are written at once when a target shows up.
+    #[must_use]
+    pub fn max_concurrent_updates(&self) -> usize {
+        self.max_concurrent_updates.unwrap_or(Self::DEFAULT_MAX_CONCURRENT_UPDATES)
     }
 
-    #[test]
-    pub fn upload_host_file_host_file() {
-        let host_file = format!(
-            "127.0.0.1	localhost
+    /// How long writing a hosts file through an exec may take before the container is given up on.
+    #[must_use]
+    pub fn exec_timeout(&self) -> Duration {
+        Duration::from_millis(self.exec_timeout_ms.unwrap_or(Self::DEFAULT_EXEC_TIMEOUT_MS))
+    }
 
-# The following lines are desirable for IPv6 capable hosts
-::1     ip6-localhost ip6-loopback
-fe00::0 ip6-localnet
-ff00::0 ip6-mcastprefix
-ff02::1 ip6-allnodes
-ff02::2 ip6-allrouters
-::1 traefik.localhost
-::1 custom_app.localhost
-### open {} network target
-### close {} network target
-1.1.1.1 aze
-", PACKAGE, PACKAGE);
-        let lines = vec![
-            "web".into(),
-            "api".into(),
-        ];
-        let s = super::update_host_file(host_file, &lines, "network".into(), "target".into(), "1.1.1.1".into());
-        assert_eq!(s,
-            format!(
-"127.0.0.1	localhost
+    #[must_use]
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(Self::DEFAULT_MAX_RETRIES)
+    }
+
+    #[must_use]
+    pub fn timestamps(&self) -> bool {
+        self.timestamps.unwrap_or(true)
+    }
+
+    #[must_use]
+    pub fn dependencies_label(&self) -> &str {
+        self.dependencies_label.as_deref().unwrap_or(Self::DEFAULT_DEPENDENCIES_LABEL)
+    }
+
+    /// The label naming the service of a container, the compose one unless overridden.
+    #[must_use]
+    pub fn service_label_key(&self) -> &str {
+        self.service_label_key.as_deref().unwrap_or(SERVICE_LABEL)
+    }
+
+    #[must_use]
+    pub fn hosts_path(&self) -> &str {
+        self.hosts_path.as_deref().unwrap_or(Self::DEFAULT_HOSTS_PATH)
+    }
+
+    #[must_use]
+    pub fn log_ignored(&self) -> bool {
+        self.log_ignored.unwrap_or(true)
+    }
+
+    #[must_use]
+    pub fn events(&self) -> bool {
+        self.events.unwrap_or(true)
+    }
+
+    /// The flagged containers, every container of the networks unless `require_flag`, and the targets, which carry no flag.
+    /// Docker matches names as unanchored patterns, so name filters are narrowed down by [`Targets`] afterwards.
+    #[must_use]
+    pub fn poll_filters(&self) -> Vec<PollFilter> {
+        let flagged = if self.require_flag() {
+            vec![PollFilter::Label(match &self.label_value {
+                Some(value) => format!("{}={}", self.label_key, value),
+                None => self.label_key.clone(),
+            })]
+        } else if self.auto_network() {
+            // no container is in a network named auto: only the targets are polled until one is picked
+            vec![]
+        } else {
+            self.network.iter().map(|network| PollFilter::Network(network.clone())).collect()
+        };
+        let label = match &self.target_match {
+            TargetMatch::Service => self.service_label_key(),
+            TargetMatch::Label(key) => key,
+            TargetMatch::Name => {
+                return flagged.into_iter()
+                    .chain(self.target.iter().map(|target| PollFilter::Name(target.clone())))
+                    .collect();
+            }
+        };
+
+        if self.target_is_regex {
+            return flagged.into_iter().chain([PollFilter::Label(label.to_string())]).collect();
+        }
+
+        flagged.into_iter()
+            .chain(self.target.iter().map(|target| PollFilter::Label(format!("{}={}", label, target))))
+            .collect()
+    }
+
+    /// Whether only containers carrying the flag are routed, rather than every container of the networks.
+    #[must_use]
+    pub fn require_flag(&self) -> bool {
+        self.require_flag.unwrap_or(true)
+    }
+
+    /// What `target` is compared with for `raw`, according to `target_match`.
+    #[must_use]
+    pub fn target_key<'a>(&self, raw: &'a RawContainer) -> Option<&'a String> {
+        match &self.target_match {
+            TargetMatch::Service => raw.labels.get(self.service_label_key()),
+            TargetMatch::Name => raw.name.as_ref(),
+            TargetMatch::Label(key) => raw.labels.get(key),
+        }
+    }
+
+    /// Whether a value of the `label_key` label flags its container: any value does unless `label_value` is set.
+    #[must_use]
+    pub fn flags(&self, value: &str) -> bool {
+        self.label_value.as_deref().is_none_or(|expected| expected == value)
+    }
+
+    /// Whether the network is left to be picked from the target, see [`AUTO_NETWORK`].
+    #[must_use]
+    pub fn auto_network(&self) -> bool {
+        self.network == [AUTO_NETWORK]
+    }
+
+    /// The first configured network `raw` has an ip in, with that ip and its ipv6 if any.
+    #[must_use]
+    pub fn locate<'a>(&'a self, raw: &'a RawContainer) -> (Option<&'a String>, Option<&'a String>, Option<&'a String>) {
+        let (network, ip) = self.network.iter()
+            .find_map(|network| raw.networks.get(network).filter(|ip| !ip.is_empty()).map(|ip| (network, ip)))
+            .unzip();
+        let ipv6 = network.and_then(|network| raw.ipv6_networks.get(network));
+
+        (network, ip, ipv6)
+    }
+
+    /// Whether a container is left alone whatever its labels, matching `exclude` on its name, its service or its id.
+    #[must_use]
+    pub fn excludes(&self, id: &str, name: Option<&str>, service: Option<&str>) -> bool {
+        self.exclude.iter().any(|excluded| Some(excluded.as_str()) == name || Some(excluded.as_str()) == service || is_id_prefix(excluded, id))
+    }
+
+    /// Compiles `target` into the patterns services are matched against, escaped unless `target_is_regex` is set.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ConfigError::Pattern`] when a target is not a valid regex.
+    pub fn targets(&self) -> Result<Targets> {
+        let patterns = self.target.iter().map(|target| {
+            let pattern = if self.target_is_regex {
+                target.clone()
+            } else {
+                format!("^{}$", regex::escape(target))
+            };
+            Regex::new(&pattern).map_err(|e| ConfigError::Pattern(e.to_string()))
+        });
+
+        Ok(Targets(patterns.collect::<std::result::Result<_, _>>()?))
+    }
+
+    #[must_use]
+    pub fn guard<'a>(&'a self, network: &'a str, target: &'a str) -> Guard<'a> {
+        Guard {
+            prefix: self.guard_prefix.as_deref().unwrap_or(Self::DEFAULT_GUARD_PREFIX),
+            network,
+            target,
+            annotate: self.annotate,
+            extra_hosts: Some(&self.extra_hosts).filter(|extra_hosts| !extra_hosts.is_empty()),
+        }
+    }
+
+    /// Whether both configs write the same guard lines, which are scoped by network and target.
+    #[must_use]
+    pub fn same_guards(&self, other: &Self) -> bool {
+        self.network == other.network
+            && self.target == other.target
+            && self.target_match == other.target_match
+            && self.guard("", "").prefix == other.guard("", "").prefix
+    }
+
+    /// Spells out every defaulted field, so the config can be shown as it is actually used.
+    #[must_use]
+    pub fn resolved(mut self) -> Self {
+        self.poll_interval_ms = Some(self.poll_interval_ms.unwrap_or(Self::DEFAULT_POLL_INTERVAL_MS));
+        self.retry_base_delay_ms = Some(self.retry_base_delay_ms.unwrap_or(Self::DEFAULT_RETRY_BASE_DELAY_MS));
+        self.max_retries = Some(self.max_retries());
+        self.max_poll_errors = Some(self.max_poll_errors());
+        self.max_concurrent_updates = Some(self.max_concurrent_updates());
+        self.exec_timeout_ms = Some(self.exec_timeout_ms.unwrap_or(Self::DEFAULT_EXEC_TIMEOUT_MS));
+        self.guard_prefix = Some(self.guard("", "").prefix.to_string());
+        self.timestamps = Some(self.timestamps());
+        self.events = Some(self.events());
+        self.dependencies_label = Some(self.dependencies_label().to_string());
+        self.hosts_path = Some(self.hosts_path().to_string());
+        self.service_label_key = Some(self.service_label_key().to_string());
+        self.require_flag = Some(self.require_flag());
+        self
+    }
+}
+
+pub trait ConfigSource {
+    /// Reads the config again.
+    ///
+    /// # Errors
+    ///
+    /// Fails when the config can no longer be read or parsed, the loop then keeps the previous one.
+    fn load(&mut self) -> Result<Config>;
+    fn changed(&self) -> bool;
+}
+
+/// The real client uploads hosts files as the archive built by [`hosts_archive`],
+/// so test doubles only need to record the content they are given to assert it.
+/// When the daemon refuses the upload because the hosts file is bind mounted and busy,
+/// it writes the file through an exec in the container instead.
+#[async_trait]
+pub trait Docker: Send + Sync {
+    /// Lists the running containers matching any of `filters`.
+    async fn poll(&self, filters: &[PollFilter]) -> Result<HashMap<String, RawContainer>>;
+    /// Fetches a single container, `None` when it no longer exists.
+    async fn inspect(&self, id: &str) -> Result<Option<RawContainer>>;
+    /// How the daemon is reached, logged once at startup rather than on every reconnection.
+    fn connection(&self) -> Option<String> {
+        None
+    }
+    async fn reconnect(&mut self) -> Result<()>;
+    /// Whether a network named exactly `name` exists.
+    async fn network_exists(&self, name: &str) -> Result<bool>;
+    /// The names of the user-defined networks, leaving out `bridge`, `host` and `none`.
+    async fn list_networks(&self) -> Result<Vec<String>>;
+    /// Leaves the hosts file alone when it already holds the wanted guard block, see `hosts_update`.
+    async fn update_hosts_for(&self, container: Container, dependencies: &[String], guard: &Guard<'_>, host: &Address) -> Result<HostsUpdate>;
+    async fn clear_hosts_for(&self, container: Container, guard: &Guard<'_>) -> Result<()>;
+
+    /// Waits until the containers may have changed, at most `timeout`.
+    async fn wait(&mut self, timeout: Duration) -> Result<()> {
+        tokio::time::sleep(timeout).await;
+        Ok(())
+    }
+
+    /// Why `wait` gave up on the daemon events and went back to polling, once.
+    fn take_events_lost(&mut self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HostsUpdate {
+    Unchanged,
+    /// Holds the diff of the written file, as given by `hosts_diff`.
+    Changed(String),
+}
+
+/// A `${` left unclosed is kept as is.
+fn expand_env(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + end];
+        let variable = std::env::var(name).map_err(|_| ConfigError::UndefinedVariable(name.to_string()))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&variable);
+        rest = &rest[start + end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether `value` is `id` or a start of it as short as the 12 characters `docker ps` shows.
+/// Shorter values are never taken as ids, so that a service named `db` does not match every id starting with it.
+fn is_id_prefix(value: &str, id: &str) -> bool {
+    value.len() >= 12 && value.chars().all(|c| c.is_ascii_hexdigit()) && id.starts_with(value)
+}
+
+/// Permissive on purpose: letters, digits, dots, hyphens and underscores, as compose service names use them.
+fn is_hostname(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// Deserialized from, and serialized as, a plain list of strings.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct StringVec {
+    inner: Vec<String>,
+}
+
+impl StringVec {
+    /// Reads a comma-separated label value, a bare `true`/`1`/`yes` flag carrying no value.
+    pub fn parse(value: &str) -> Option<Self> {
+        if matches!(value.trim(), "" | "true" | "1" | "yes") {
+            return None;
+        }
+
+        let inner: Vec<String> = value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(ToString::to_string).collect();
+
+        (!inner.is_empty()).then_some(Self { inner })
+    }
+
+    /// Reads compose's `depends_on` label, `service:condition:restart` entries separated by commas.
+    pub fn depends_on(value: &str) -> Self {
+        let inner = value
+            .split(',')
+            .filter_map(|entry| entry.split(':').next())
+            .map(str::trim)
+            .filter(|service| !service.is_empty())
+            .map(ToString::to_string)
+            .collect();
+
+        Self { inner }
+    }
+
+    #[must_use]
+    pub fn as_slice(&self) -> &[String] {
+        &self.inner
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+impl Display for StringVec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        write!(f, "{}", self.inner.join(", "))?;
+        write!(f, "]")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RawContainer {
+    pub id: String,
+    pub name: Option<String>,
+    pub networks: HashMap<String, String>,
+    pub ipv6_networks: HashMap<String, String>,
+    pub labels: HashMap<String, String>,
+    /// Creation time, in seconds since the epoch.
+    pub created: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct Container {
+    id: String,
+    name: Option<String>,
+    service: Option<String>,
+    network: Option<String>,
+    ip: Option<String>,
+    ipv6: Option<String>,
+    flag: Option<String>,
+    aliases: Option<StringVec>,
+    created: Option<i64>,
+    /// The target it stands for, only set on targets.
+    target: Option<String>,
+    /// Read from [`ALIAS_LABEL`], ignored unless a valid hostname.
+    alias: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Address {
+    pub ip: String,
+    pub ipv6: Option<String>,
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ip)?;
+
+        if let Some(ipv6) = &self.ipv6 {
+            write!(f, " and {}", ipv6)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Container {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "container {}", self.hash())?;
+
+        if self.flag.is_some() {
+            write!(f, " flagged")?;
+        } else {
+            write!(f, " unflagged")?;
+        }
+
+        if let Some(service) = &self.service {
+            write!(f, " service {}", service)?;
+        }
+
+        if let Some(name) = &self.name {
+            write!(f, " named {}", name)?;
+        } else {
+            write!(f, " unnamed")?;
+        }
+
+        if let (Some(network), Some(address)) = (&self.network, self.address()) {
+            write!(f, " in network {} at ip {}", network, address)?;
+        } else {
+            write!(f, " orphan")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Container {
+    /// The hostnames to route for this container: its own aliases, else the configured dependencies.
+    #[must_use]
+    pub fn dependencies<'a>(&'a self, config: &'a Config) -> &'a [String] {
+        self.aliases.as_ref().unwrap_or(&config.dependencies).as_slice()
+    }
+
+    #[must_use]
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    #[must_use]
+    pub fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    /// The first 16 characters of the id, all of it when shorter.
+    #[must_use]
+    pub fn hash(&self) -> &str {
+        self.id.get(0..16).unwrap_or(&self.id)
+    }
+
+    #[must_use]
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_deref()
+    }
+
+    #[must_use]
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+
+    /// The name other containers know it by: its alias, else its service, else its name.
+    #[must_use]
+    pub fn alias(&self) -> Option<String> {
+        self.alias.clone().or_else(|| self.service.clone()).or_else(|| self.name())
+    }
+
+    #[must_use]
+    pub fn flag(&self) -> Option<&str> {
+        self.flag.as_deref()
+    }
+
+    #[must_use]
+    pub fn address(&self) -> Option<Address> {
+        self.ip.clone().map(|ip| Address { ip, ipv6: self.ipv6.clone() })
+    }
+}
+
+enum StackEvents {
+    New(Container),
+    Target(Container, Vec<Container>, String, Address),
+    Gone(Container),
+    /// The container recorded for a target went away.
+    TargetGone(Container, String),
+    LostNetwork(Container),
+    NoFlag(Container),
+    OutsideNetwork(Container),
+    Excluded(Container),
+}
+
+/// The compiled `target` patterns, literal ones being anchored and escaped.
+pub struct Targets(Vec<Regex>);
+
+impl Targets {
+    #[must_use]
+    pub fn matches(&self, service: &str) -> bool {
+        self.0.iter().any(|pattern| pattern.is_match(service))
+    }
+}
+
+/// How a container seen for the first time is handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Classification {
+    /// Matches `exclude`, which wins over any label.
+    Excluded,
+    /// A target service with an ip in one of the networks.
+    Target,
+    /// Carries the flag and has an ip in one of the networks.
+    Flagged,
+    /// Has an ip in one of the networks but no flag.
+    NoFlag,
+    /// Has no ip in any of the networks.
+    Outside,
+}
+
+pub fn classify(raw: &RawContainer, config: &Config, targets: &Targets) -> Classification {
+    let service = raw.labels.get(config.service_label_key());
+    let (_, ip, _) = config.locate(raw);
+
+    if config.excludes(&raw.id, raw.name.as_deref(), service.map(String::as_str)) {
+        Classification::Excluded
+    } else if ip.is_none() {
+        Classification::Outside
+    } else if config.target_key(raw).is_some_and(|key| targets.matches(key)) {
+        Classification::Target
+    } else if !config.require_flag() || raw.labels.get(&config.label_key).is_some_and(|value| config.flags(value)) {
+        Classification::Flagged
+    } else {
+        Classification::NoFlag
+    }
+}
+
+/// What one poll went through, returned by `loop_once` and logged as the heartbeat line ending it.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct TickReport {
+    /// Flagged containers seen for the first time.
+    new: usize,
+    target: usize,
+    gone: usize,
+    ignored: usize,
+    /// Hosts files written.
+    updated: usize,
+    /// Hosts files that could not be written.
+    errors: usize,
+}
+
+impl TickReport {
+    fn of(events: &[StackEvents]) -> Self {
+        let mut report = Self::default();
+        for event in events {
+            match event {
+                StackEvents::New(_) => report.new += 1,
+                StackEvents::Target(..) => report.target += 1,
+                StackEvents::Gone(_) | StackEvents::TargetGone(..) => report.gone += 1,
+                StackEvents::NoFlag(_) | StackEvents::OutsideNetwork(_) | StackEvents::Excluded(_) => report.ignored += 1,
+                StackEvents::LostNetwork(_) => {}
+            }
+        }
+        report
+    }
+
+    fn record(&mut self, routed: Routed) {
+        match routed {
+            Routed::Written => self.updated += 1,
+            Routed::Failed => self.errors += 1,
+            Routed::Unchanged | Routed::Skipped => {}
+        }
+    }
+}
+
+impl Display for TickReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "poll complete: {} flagged, {} target, {} gone, {} ignored, {} updates applied, {} errors",
+            self.new, self.target, self.gone, self.ignored, self.updated, self.errors
+        )
+    }
+}
+
+struct CurrentStack {
+    config: Config,
+    targets: Targets,
+    target_ip: HashMap<String, Address>,
+    map: Option<HashMap<String, Container>>,
+    metrics: Arc<Metrics>,
+    /// Set until the first poll went through when `reconcile_on_start` is.
+    reconciling: bool,
+    /// The address and hostnames last found in place, by container id then target service.
+    applied: HashMap<String, HashMap<String, (Address, Vec<String>)>>,
+    /// Set until the first poll went through when targets were read back from `target_file`.
+    restoring: bool,
+    /// Whether `network` was configured as [`AUTO_NETWORK`], even once picked.
+    auto_network: bool,
+    /// Containers not known yet, with their classification and how many polls in a row it held, see `debounce_polls`.
+    pending: HashMap<String, (Classification, u32)>,
+    /// Found before there was anywhere to log them, logged on the next poll.
+    warnings: Vec<String>,
+}
+
+/// Stands as the target of the guard blocks written in mesh mode.
+const MESH_TARGET: &str = "mesh";
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn is_connection_error(error: &Error) -> bool {
+    matches!(error, BusinessError::Docker(DockerError::Connection(_)))
+}
+
+fn is_gone_error(error: &Error) -> bool {
+    matches!(error, BusinessError::Docker(DockerError::NoSuchContainer(_)))
+}
+
+fn is_transient_error(error: &Error) -> bool {
+    matches!(error, BusinessError::Docker(DockerError::Transient(_)))
+}
+
+async fn with_backoff<T, W, F, Fut>(f: &mut W, config: &Config, mut operation: F) -> Result<T>
+where
+    W: LogWrite,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = config.retry_base_delay();
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Err(e) if attempt < config.max_retries() && is_transient_error(&e) => {
+                attempt += 1;
+                text(f, config, Level::Warn, format_args!("{}, retrying in {}ms (attempt {}/{})", e, delay.as_millis(), attempt, config.max_retries()))?;
+                json(f, config, Level::Warn, json!({"event": "retry", "error": e.to_string(), "delay_ms": delay.as_millis(), "attempt": attempt}))?;
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn reconnect<D: Docker, W: LogWrite>(docker: &mut D, f: &mut W, config: &Config, error: &Error, attempt: u32) -> Result<()> {
+    text(f, config, Level::Warn, format_args!("{}, reconnecting (attempt {}/{})", error, attempt, RECONNECT_ATTEMPTS))?;
+    json(f, config, Level::Warn, json!({"event": "reconnect", "error": error.to_string(), "attempt": attempt}))?;
+    tokio::time::sleep(RECONNECT_DELAY).await;
+    docker.reconnect().await
+}
+
+async fn poll<D: Docker, W: LogWrite>(docker: &mut D, f: &mut W, config: &Config) -> Result<HashMap<String, RawContainer>> {
+    let mut attempt = 0;
+    loop {
+        let filters = config.poll_filters();
+        match with_backoff(f, config, || docker.poll(&filters)).await {
+            Err(e) if attempt < RECONNECT_ATTEMPTS && is_connection_error(&e) => {
+                attempt += 1;
+                reconnect(docker, f, config, &e, attempt).await?;
+            }
+            Ok(containers) if config.inspect_missing_ip => return inspect_missing_ips(docker, config, containers).await,
+            result => return result,
+        }
+    }
+}
+
+/// The listing can show a container attached to our network before its ip is assigned: ask for it again.
+async fn inspect_missing_ips<D: Docker>(docker: &D, config: &Config, mut containers: HashMap<String, RawContainer>) -> Result<HashMap<String, RawContainer>> {
+    let missing: Vec<String> = containers
+        .values()
+        .filter(|raw| config.network.iter().any(|network| raw.networks.get(network).is_some_and(String::is_empty)))
+        .map(|raw| raw.id.clone())
+        .collect();
+
+    for id in missing {
+        match docker.inspect(&id).await? {
+            Some(raw) => containers.insert(id, raw),
+            None => containers.remove(&id),
+        };
+    }
+
+    Ok(containers)
+}
+
+/// What became of the hosts file of one container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Routed {
+    Written,
+    Unchanged,
+    /// Left alone on a dry run, or as the container went away meanwhile.
+    Skipped,
+    /// Updating it failed, which was logged.
+    Failed,
+}
+
+impl Routed {
+    /// Whether the hosts file now holds what was asked for.
+    fn in_place(self) -> bool {
+        matches!(self, Routed::Written | Routed::Unchanged)
+    }
+}
+
+/// The hostnames written for `container`: its dependencies, suffixed, then the target alias taken as is.
+/// Alias to ip of `containers`. Several sharing an alias, as replicas do, leave the first one,
+/// the others being reported by `hostname_conflicts` rather than silently overwriting it.
+fn alias_table<'a>(containers: impl IntoIterator<Item = &'a Container>) -> BTreeMap<String, String> {
+    let mut table = BTreeMap::new();
+    for container in containers {
+        if let (Some(alias), Some(ip)) = (container.alias(), container.ip()) {
+            table.entry(alias).or_insert_with(|| ip.to_string());
+        }
+    }
+    table
+}
+
+fn hostnames(config: &Config, container: &Container) -> Vec<String> {
+    let mut hostnames = suffixed(container.dependencies(config), config.dependency_suffix.as_deref());
+    if let Some(alias) = config.target_alias.as_ref().filter(|alias| !hostnames.contains(alias)) {
+        hostnames.push(alias.clone());
+    }
+    hostnames
+}
+
+async fn update_hosts_for<D: Docker, W: LogWrite>(docker: &mut D, f: &mut W, config: &Config, container: &Container, network: &str, target: &str, host: &Address) -> Result<Routed> {
+    let guard = config.guard(network, target);
+    let dependencies = hostnames(config, container);
+
+    if config.dry_run {
+        let block = guard_block(&dependencies, &guard, &host.ip, host.ipv6.as_deref());
+        text(f, config, Level::Info, format_args!("dry run: would write to container {}:\n{}", container.hash(), block.trim_end()))?;
+        json(f, config, Level::Info, json!({"event": "dry_run", "container": container.hash(), "block": block}))?;
+        return Ok(Routed::Skipped);
+    }
+
+    let mut attempt = 0;
+    loop {
+        match with_backoff(f, config, || docker.update_hosts_for(container.clone(), &dependencies, &guard, host)).await {
+            Err(e) if attempt < RECONNECT_ATTEMPTS && is_connection_error(&e) => {
+                attempt += 1;
+                reconnect(docker, f, config, &e, attempt).await?;
+            }
+            Ok(update) => return report(f, config, container, update),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn report<W: LogWrite>(f: &mut W, config: &Config, container: &Container, update: HostsUpdate) -> Result<Routed> {
+    match update {
+        HostsUpdate::Changed(diff) => {
+            text(f, config, Level::Debug, format_args!("changed /etc/hosts of container {}:\n{}", container.hash(), diff.trim_end()))?;
+            json(f, config, Level::Debug, json!({"event": "hosts_diff", "container": container.hash(), "diff": diff}))?;
+            Ok(Routed::Written)
+        }
+        HostsUpdate::Unchanged => {
+            text(f, config, Level::Info, format_args!("hosts already up to date for container {}", container.hash()))?;
+            json(f, config, Level::Info, json!({"event": "unchanged", "container": container.hash()}))?;
+            Ok(Routed::Unchanged)
+        }
+    }
+}
+
+fn skip<W: LogWrite>(f: &mut W, config: &Config, container: &Container, e: &Error) -> Result<Routed> {
+    text(f, config, Level::Error, format_args!("could not update /etc/hosts for container {}, skipping it: {}", container.hash(), e))?;
+    json(f, config, Level::Error, json!({"event": "update_failed", "container": container.hash(), "error": e.to_string()}))?;
+    Ok(Routed::Failed)
+}
+
+/// Writes in `container` a block holding only the `extra_hosts` of `guard`, as the mesh and the target get.
+async fn write_extra_hosts<D: Docker, W: LogWrite>(docker: &D, f: &mut W, config: &Config, container: &Container, guard: &Guard<'_>) -> Result<Routed> {
+    let host = container.address().expect("routed containers have an ip");
+
+    if config.dry_run {
+        let block = guard_block(&[], guard, &host.ip, None);
+        text(f, config, Level::Info, format_args!("dry run: would write to container {}:\n{}", container.hash(), block.trim_end()))?;
+        json(f, config, Level::Info, json!({"event": "dry_run", "container": container.hash(), "block": block}))?;
+        return Ok(Routed::Skipped);
+    }
+
+    match docker.update_hosts_for(container.clone(), &[], guard, &host).await {
+        Ok(update) => report(f, config, container, update),
+        Err(e) if is_gone_error(&e) => Ok(Routed::Skipped),
+        Err(e) => skip(f, config, container, &e),
+    }
+}
+
+/// Like [`update_hosts_for`], but a container failing on its own is logged and skipped rather than stopping the loop.
+/// Losing the daemon stays fatal, since every other container would fail the same way.
+async fn route<D: Docker, W: LogWrite>(docker: &mut D, f: &mut W, config: &Config, container: &Container, network: &str, target: &str, host: &Address) -> Result<Routed> {
+    match update_hosts_for(docker, f, config, container, network, target, host).await {
+        Err(e) if !is_connection_error(&e) => skip(f, config, container, &e),
+        result => result,
+    }
+}
+
+/// Routes every container in `containers` to `target`, `max_concurrent_updates` at a time.
+/// Outcomes are logged afterwards in the order of `containers`; the ones worth retrying go through [`route`] one by one.
+async fn route_all<D: Docker, W: LogWrite>(docker: &mut D, f: &mut W, config: &Config, containers: &[Container], target: &str, host: &Address) -> Result<Vec<Routed>> {
+    let network = |container: &Container| container.network.clone().expect("routed containers are in a network");
+    let mut routed = Vec::with_capacity(containers.len());
+
+    if config.dry_run || config.max_concurrent_updates() <= 1 {
+        for container in containers {
+            routed.push(route(docker, f, config, container, &network(container), target, host).await?);
+        }
+        return Ok(routed);
+    }
+
+    let shared = &*docker;
+    let updates: Vec<Result<HostsUpdate>> = futures_util::stream::iter(containers)
+        .map(|container| async move {
+            let network = network(container);
+            let dependencies = hostnames(config, container);
+            shared.update_hosts_for(container.clone(), &dependencies, &config.guard(&network, target), host).await
+        })
+        .buffered(config.max_concurrent_updates())
+        .collect()
+        .await;
+
+    for (container, update) in containers.iter().zip(updates) {
+        routed.push(match update {
+            Ok(update) => report(f, config, container, update)?,
+            Err(e) if is_connection_error(&e) || is_transient_error(&e) => route(docker, f, config, container, &network(container), target, host).await?,
+            Err(e) => skip(f, config, container, &e)?,
+        });
+    }
+    Ok(routed)
+}
+
+impl CurrentStack {
+    async fn loop_once<D: Docker, W: LogWrite>(&mut self, docker: &mut D, f: &mut W) -> Result<TickReport> {
+        for warning in std::mem::take(&mut self.warnings) {
+            text(f, &self.config, Level::Warn, format_args!("{}", warning))?;
+            json(f, &self.config, Level::Warn, json!({"event": "warning", "warning": warning}))?;
+        }
+        let containers = poll(docker, f, &self.config).await.inspect_err(|_| self.metrics.poll_error())?;
+        if !self.pick_network(docker, f, &containers).await? {
+            return Ok(TickReport::default());
+        }
+        if let Some(max) = self.config.max_containers {
+            let managed = containers.values()
+                .filter(|raw| matches!(classify(raw, &self.config, &self.targets), Classification::Target | Classification::Flagged))
+                .count();
+            if managed > max {
+                // nothing is recorded either, so the containers are all new again once under the limit
+                text(f, &self.config, Level::Error, format_args!("polled {} flagged and target containers, more than max_containers {}, skipping this poll", managed, max))?;
+                json(f, &self.config, Level::Error, json!({"event": "too_many_containers", "containers": managed, "max": max}))?;
+                return Ok(TickReport::default());
+            }
+        }
+        let polled = containers.len();
+        let targets = self.target_ip.clone();
+        let events = self.actualize(containers);
+        if std::mem::take(&mut self.restoring) {
+            self.forget_stale_targets(f)?;
+        }
+        let changed = !events.is_empty();
+        text(f, &self.config, Level::Debug, format_args!("polled {} containers, {} events", polled, events.len()))?;
+        let mut report = TickReport::of(&events);
+        // containers already routed by a previous run are only rewritten if their block is stale,
+        // so the first poll merely checks them and only reports warnings and the overall outcome
+        let reconciling = std::mem::take(&mut self.reconciling);
+        let verbosity = self.config.verbosity;
+        if reconciling {
+            self.config.verbosity = Verbosity::Quiet;
+        }
+        let applied = self.apply_events(docker, f, events, &mut report).await;
+        self.config.verbosity = verbosity;
+        applied?;
+
+        if changed {
+            for (hostname, containers) in self.hostname_conflicts() {
+                let config = &self.config;
+                text(f, config, Level::Warn, format_args!("hostname {} points at different ips in containers {}", hostname, containers.join(", ")))?;
+                json(f, config, Level::Warn, json!({"event": "hostname_conflict", "hostname": hostname, "containers": containers}))?;
+            }
+        }
+
+        if reconciling {
+            text(f, &self.config, Level::Info, format_args!("reconciled {} flagged containers with their hosts files, {} rewritten", report.new, report.updated))?;
+            json(f, &self.config, Level::Info, json!({"event": "reconciled", "flagged": report.new, "updates": report.updated}))?;
+        }
+
+        let level = if self.config.summary { Level::Info } else { Level::Debug };
+        text(f, &self.config, level, format_args!("{}", report))?;
+        json(f, &self.config, level, json!({"event": "summary", "flagged": report.new, "targets": report.target, "gone": report.gone, "ignored": report.ignored, "updates": report.updated, "errors": report.errors}))?;
+
+        if self.target_ip != targets {
+            self.save_targets(f)?;
+        }
+
+        let flagged = self.map.iter().flat_map(HashMap::values).filter(|item| item.flag().is_some()).count();
+        self.metrics.set_flagged(flagged);
+        self.metrics.set_target_known(!self.target_ip.is_empty());
+
+        Ok(report)
+    }
+}
+
+impl CurrentStack {
+    /// Handles the events of a poll, then writes the mesh if any changed.
+    async fn apply_events<D: Docker, W: LogWrite>(&mut self, docker: &mut D, f: &mut W, events: Vec<StackEvents>, report: &mut TickReport) -> Result<()> {
+        let changed = !events.is_empty();
+
+        for event in events {
+            let routed = match event {
+                StackEvents::Target(container, known, service, ip) => self.target_found(docker, f, &container, known, (service, ip)).await?,
+                StackEvents::New(container) => self.container_found(docker, f, &container).await?,
+                StackEvents::Gone(container) => {
+                    self.container_gone(docker, f, &container).await?;
+                    vec![]
+                }
+                StackEvents::TargetGone(container, target) => {
+                    self.target_gone(docker, f, &container, &target).await?;
+                    vec![]
+                }
+                StackEvents::LostNetwork(container) => {
+                    self.network_lost(docker, f, &container).await?;
+                    vec![]
+                }
+                // only raised by actualize the first time a container is seen, so these never repeat
+                StackEvents::NoFlag(container) if self.config.log_ignored() => {
+                    text(f, &self.config, Level::Info, format_args!("event container ignored (label): {}", container))?;
+                    json(f, &self.config, Level::Info, json!({"event": "ignored", "reason": "label", "container": container.hash()}))?;
+                    vec![]
+                }
+                StackEvents::OutsideNetwork(container) if self.config.log_ignored() => {
+                    text(f, &self.config, Level::Info, format_args!("event container ignored (network): {}", container))?;
+                    json(f, &self.config, Level::Info, json!({"event": "ignored", "reason": "network", "container": container.hash()}))?;
+                    vec![]
+                }
+                StackEvents::Excluded(container) if self.config.log_ignored() => {
+                    text(f, &self.config, Level::Info, format_args!("event container ignored (excluded): {}", container))?;
+                    json(f, &self.config, Level::Info, json!({"event": "ignored", "reason": "excluded", "container": container.hash()}))?;
+                    vec![]
+                }
+                StackEvents::NoFlag(_) | StackEvents::OutsideNetwork(_) | StackEvents::Excluded(_) => vec![],
+            };
+            self.record(report, routed);
+        }
+
+        if changed && self.config.mesh {
+            let routed = self.mesh(docker, f, &self.config).await?;
+            self.record(report, routed);
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, report: &mut TickReport, routed: Vec<Routed>) {
+        for routed in routed {
+            if routed == Routed::Written {
+                self.metrics.update();
+            }
+            report.record(routed);
+        }
+    }
+
+    /// `known` holds every routable container, those matched before any target was known included.
+    async fn target_found<D: Docker, W: LogWrite>(&mut self, docker: &mut D, f: &mut W, container: &Container, known: Vec<Container>, (service, ip): (String, Address)) -> Result<Vec<Routed>> {
+        let config = &self.config;
+        text(f, config, Level::Notice, format_args!("event found target: {} applying it to known {} containers", container, known.len()))?;
+        json(f, config, Level::Notice, json!({"event": "target", "container": container.hash(), "service": service, "ip": ip.ip, "ipv6": ip.ipv6, "applied_to": known.len()}))?;
+        for item in &known {
+            text(f, config, Level::Info, format_args!("updating previous container {}", item.hash()))?;
+        }
+        let mut routed = route_all(docker, f, config, &known, &service, &ip).await?;
+        for (item, routed) in known.into_iter().zip(&routed) {
+            if routed.in_place() {
+                self.applied.entry(item.id()).or_default().insert(service.clone(), (ip.clone(), hostnames(config, &item)));
+            }
+        }
+        if config.route_into_target {
+            routed.push(self.route_into_target(docker, f, config, &service, &ip).await?);
+        }
+        text(f, config, Level::Notice, format_args!("recording ip for target {}: {}", service, ip))?;
+        self.target_ip.insert(service, ip);
+        Ok(routed)
+    }
+
+    async fn container_found<D: Docker, W: LogWrite>(&mut self, docker: &mut D, f: &mut W, container: &Container) -> Result<Vec<Routed>> {
+        let config = &self.config;
+        text(f, config, Level::Info, format_args!("event container match: {}", container))?;
+        json(f, config, Level::Info, json!({"event": "new", "container": container.hash(), "targets": self.target_ip.keys().collect::<Vec<_>>()}))?;
+        if self.target_ip.is_empty() {
+            text(f, config, Level::Warn, format_args!("could not update /etc/hosts for container {} because no target known yet, it will be once one is found", container.hash()))?;
+        }
+        let network = container.network.clone().expect("matched containers are in a network");
+        let reached: Vec<_> = self.target_ip.iter()
+            .filter(|(service, ip)| self.reaches(container, service, ip))
+            .map(|(service, ip)| (service.clone(), ip.clone()))
+            .collect();
+        let mut all = vec![];
+        for (service, ip) in reached {
+            text(f, config, Level::Info, format_args!("updating /etc/hosts for container {} with target {}", container.hash(), service))?;
+            let routed = route(docker, f, config, container, &network, &service, &ip).await?;
+            all.push(routed);
+            if config.route_into_target {
+                all.push(self.route_into_target(docker, f, config, &service, &ip).await?);
+            }
+            if routed.in_place() {
+                self.applied.entry(container.id()).or_default().insert(service, (ip, hostnames(config, container)));
+            }
+        }
+        Ok(all)
+    }
+
+    async fn container_gone<D: Docker, W: LogWrite>(&mut self, docker: &D, f: &mut W, container: &Container) -> Result<()> {
+        let config = &self.config;
+        text(f, config, Level::Info, format_args!("event container gone: {}", container))?;
+        json(f, config, Level::Info, json!({"event": "gone", "container": container.hash()}))?;
+        self.applied.remove(&container.id());
+        if config.cleanup_on_gone && container.flag().is_some() {
+            self.clear_container(docker, f, container).await?;
+        }
+        Ok(())
+    }
+
+    async fn target_gone<D: Docker, W: LogWrite>(&mut self, docker: &D, f: &mut W, container: &Container, target: &str) -> Result<()> {
+        let config = &self.config;
+        text(f, config, Level::Warn, format_args!("event target gone: {}, forgetting the ip of {}", container, target))?;
+        json(f, config, Level::Warn, json!({"event": "target_gone", "container": container.hash(), "service": target}))?;
+        if config.cleanup_on_target_gone {
+            self.clear_target(docker, f, target).await?;
+        }
+        self.target_ip.remove(target);
+        for applied in self.applied.values_mut() {
+            applied.remove(target);
+        }
+        Ok(())
+    }
+
+    async fn network_lost<D: Docker, W: LogWrite>(&mut self, docker: &D, f: &mut W, container: &Container) -> Result<()> {
+        let config = &self.config;
+        text(f, config, Level::Warn, format_args!("event container lost network: {}", container))?;
+        json(f, config, Level::Warn, json!({"event": "lost_network", "container": container.hash()}))?;
+        if config.cleanup_on_lost_network {
+            self.clear_container(docker, f, container).await?;
+            self.applied.remove(&container.id());
+        }
+        Ok(())
+    }
+}
+
+impl CurrentStack {
+    /// The targets and the flagged containers, with what was last found in place in each of them.
+    fn state(&self) -> Value {
+        let targets: serde_json::Map<String, Value> = self.target_ip.iter()
+            .map(|(service, address)| (service.clone(), json!({"ip": address.ip, "ipv6": address.ipv6})))
+            .collect();
+        let mut flagged: Vec<_> = self.map.iter().flat_map(HashMap::values).filter(|item| item.flag().is_some()).collect();
+        flagged.sort_by_key(|item| item.hash());
+        let containers: Vec<Value> = flagged.into_iter().map(|item| {
+            let applied: serde_json::Map<String, Value> = self.applied.get(&item.id()).into_iter().flatten()
+                .map(|(target, (address, hostnames))| (target.clone(), json!({"ip": address.ip, "ipv6": address.ipv6, "hostnames": hostnames})))
+                .collect();
+            json!({"container": item.hash(), "name": item.name(), "service": item.service(), "network": item.network, "ip": item.ip(), "applied": applied})
+        }).collect();
+
+        json!({"targets": targets, "containers": containers})
+    }
+
+    /// Writes the state to `state_file` when set, logs it otherwise.
+    fn dump_state<W: LogWrite>(&self, f: &mut W) -> Result<()> {
+        let state = self.state();
+        match &self.config.state_file {
+            Some(path) => {
+                if let Err(e) = std::fs::write(path, format!("{:#}\n", state)) {
+                    text(f, &self.config, Level::Warn, format_args!("could not write state file {}: {}", path, e))?;
+                    json(f, &self.config, Level::Warn, json!({"event": "state_file_failed", "path": path, "error": e.to_string()}))?;
+                }
+                Ok(())
+            }
+            None => {
+                text(f, &self.config, Level::Notice, format_args!("state: {}", state))?;
+                json(f, &self.config, Level::Notice, json!({"event": "state", "state": state}))
+            }
+        }
+    }
+
+    /// Targets read back from `target_file` whose container is gone are not routed to.
+    fn forget_stale_targets<W: LogWrite>(&mut self, f: &mut W) -> Result<()> {
+        let map = self.map.as_ref().expect("actualized");
+        let stale: Vec<String> = self.target_ip.iter()
+            .filter(|(service, address)| !map.values().any(|item| item.target.as_ref() == Some(*service) && item.address().as_ref() == Some(*address)))
+            .map(|(service, _)| service.clone())
+            .collect();
+
+        for service in stale {
+            text(f, &self.config, Level::Info, format_args!("forgetting restored target {}, its container is gone", service))?;
+            json(f, &self.config, Level::Info, json!({"event": "stale_target", "service": service}))?;
+            self.target_ip.remove(&service);
+        }
+
+        Ok(())
+    }
+
+    fn save_targets<W: LogWrite>(&self, f: &mut W) -> Result<()> {
+        let Some(path) = &self.config.target_file else {
+            return Ok(());
+        };
+
+        if let Err(e) = std::fs::write(path, format!("{:#}\n", json!(self.target_ip))) {
+            text(f, &self.config, Level::Warn, format_args!("could not write target file {}: {}", path, e))?;
+            json(f, &self.config, Level::Warn, json!({"event": "target_file_failed", "path": path, "error": e.to_string()}))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops everything known, so the next poll sees every container anew.
+    fn forget(&mut self) {
+        self.map = Some(HashMap::default());
+        self.target_ip.clear();
+        self.applied.clear();
+        self.pending.clear();
+    }
+
+    async fn clear<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W) -> Result<()> {
+        let known = self.map.iter().flat_map(HashMap::values).filter(|item| item.flag().is_some());
+
+        for container in known {
+            self.clear_container(docker, f, container).await?;
+        }
+        if self.config.route_into_target {
+            for target in self.map.iter().flat_map(HashMap::values) {
+                if let Some(service) = &target.target {
+                    self.clear_guard(docker, f, target, service).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn clear_container<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W, container: &Container) -> Result<()> {
+        for service in self.target_ip.keys() {
+            self.clear_guard(docker, f, container, service).await?;
+        }
+        if self.config.mesh {
+            self.clear_guard(docker, f, container, MESH_TARGET).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes in every flagged container a block resolving the other flagged containers of its network.
+    /// Peers are named after their [`Container::alias`], see [`alias_table`] for replicas sharing one.
+    async fn mesh<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W, config: &Config) -> Result<Vec<Routed>> {
+        let mut flagged: Vec<_> = self.map.iter().flat_map(HashMap::values).filter(|item| item.flag().is_some() && item.ip().is_some()).collect();
+        flagged.sort_by(|left, right| left.id.cmp(&right.id));
+        let mut routed = Vec::with_capacity(flagged.len());
+
+        for container in &flagged {
+            let peers = alias_table(flagged.iter().copied().filter(|peer| peer.id != container.id && peer.network == container.network));
+            let network = container.network.as_deref().expect("routed containers are in a network");
+            let guard = Guard { extra_hosts: Some(&peers), ..config.guard(network, MESH_TARGET) };
+            routed.push(write_extra_hosts(docker, f, config, container, &guard).await?);
+        }
+
+        Ok(routed)
+    }
+
+    /// Writes in the target of `service` found at `address` a block resolving the flagged containers able to reach it,
+    /// by their [`Container::alias`], see [`alias_table`] for dependents sharing one.
+    async fn route_into_target<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W, config: &Config, service: &str, address: &Address) -> Result<Routed> {
+        let map = self.map.as_ref().expect("actualized");
+        let Some(target) = map.values().find(|item| item.target.as_deref() == Some(service) && item.address().as_ref() == Some(address)) else {
+            return Ok(Routed::Skipped);
+        };
+        let dependents = alias_table(&Self::routable(map, target));
+        let network = target.network.as_deref().expect("targets are in a network");
+        let guard = Guard { extra_hosts: Some(&dependents), ..config.guard(network, service) };
+
+        write_extra_hosts(docker, f, config, target, &guard).await
+    }
+
+    /// Removes the blocks routing to `target` from every flagged container.
+    async fn clear_target<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W, target: &str) -> Result<()> {
+        for container in self.map.iter().flat_map(HashMap::values).filter(|item| item.flag().is_some()) {
+            self.clear_guard(docker, f, container, target).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn clear_guard<D: Docker, W: LogWrite>(&self, docker: &D, f: &mut W, container: &Container, target: &str) -> Result<()> {
+        let Some(network) = &container.network else {
+            return Ok(());
+        };
+
+        match docker.clear_hosts_for(container.clone(), &self.config.guard(network, target)).await {
+            Ok(()) => {
+                text(f, &self.config, Level::Info, format_args!("cleared /etc/hosts for container {}", container.hash()))?;
+                json(f, &self.config, Level::Info, json!({"event": "cleared", "container": container.hash()}))?;
+            }
+            Err(e) if is_gone_error(&e) => {}
+            Err(e) => {
+                text(f, &self.config, Level::Warn, format_args!("could not clear /etc/hosts for container {}: {}", container.hash(), e))?;
+                json(f, &self.config, Level::Warn, json!({"event": "clear_failed", "container": container.hash(), "error": e.to_string()}))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CurrentStack {
+    fn new(config: Config) -> Self {
+        let mut warnings = config.warnings();
+        let target_ip = match config.target_file.as_deref().map(load_targets) {
+            Some(Ok(target_ip)) => target_ip,
+            Some(Err(warning)) => {
+                warnings.push(warning);
+                HashMap::default()
+            }
+            None => HashMap::default(),
+        };
+
+        Self {
+            targets: config.targets().expect("config is validated"),
+            reconciling: config.reconcile_on_start,
+            restoring: !target_ip.is_empty(),
+            auto_network: config.auto_network(),
+            pending: HashMap::default(),
+            config,
+            applied: HashMap::default(),
+            map: Some(HashMap::default()),
+            target_ip,
+            metrics: Arc::default(),
+            warnings,
+        }
+    }
+}
+
+/// A missing file means no target was known yet, an unreadable one is ignored with the warning to log.
+fn load_targets(path: &str) -> std::result::Result<HashMap<String, Address>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::default()),
+        Err(e) => return Err(format!("could not read target file {}: {}", path, e)),
+    };
+
+    serde_json::from_str(&content).map_err(|e| format!("ignoring target file {}: {}", path, e))
+}
+
+impl CurrentStack {
+    fn actualize(&mut self, mut raw_containers: HashMap<String, RawContainer>) -> Vec<StackEvents> {
+        if let Some(project) = &self.config.project {
+            raw_containers.retain(|_, raw| raw.labels.get(PROJECT_LABEL) == Some(project));
+        }
+
+        let mut events = vec![];
+        events.reserve(raw_containers.len());
+
+        let known_containers = self.map.take().expect("start");
+        let mut new_containers = HashMap::default();
+
+        let mut moved_targets = vec![];
+        let mut rejoined = vec![];
+
+        for (id, mut container) in known_containers {
+            if let Some(raw) = raw_containers.remove(&id) {
+                let (network, ip, ipv6) = self.config.locate(&raw);
+                let previous = container.address();
+                let target = self.config.target_key(&raw).filter(|key| self.targets.matches(key)).cloned();
+
+                if previous.is_some() && ip.is_none() && container.flag().is_some() {
+                    // keeps the network it left, which is the one its guard blocks are written for
+                    events.push(StackEvents::LostNetwork(container.clone()));
+                }
+
+                container.network = network.cloned();
+                container.ip = ip.cloned();
+                container.ipv6 = ipv6.cloned();
+                container.target.clone_from(&target);
+
+                match (target, container.address()) {
+                    (Some(target), Some(address)) if Some(&address) != previous.as_ref() => {
+                        moved_targets.push((container.clone(), target, address));
+                    }
+                    // back in the network after losing it, so routed again like a new one
+                    (None, Some(_)) if previous.is_none() && container.flag().is_some() => rejoined.push(container.clone()),
+                    _ => {}
+                }
+
+                new_containers.insert(id.clone(), container);
+            } else if let Some(target) = container.target.clone().filter(|target| {
+                self.target_ip.get(target).is_some_and(|address| Some(address) == container.address().as_ref())
+            }) {
+                events.push(StackEvents::TargetGone(container, target));
+            } else {
+                events.push(StackEvents::Gone(container));
+            }
+        }
+
+        for (container, service, ip) in moved_targets {
+            let known = Self::routable(&new_containers, &container);
+            events.push(StackEvents::Target(container, known, service, ip));
+        }
+        events.extend(rejoined.into_iter().map(StackEvents::New));
+
+        // targets go first, so flagged containers of the same poll find them already known
+        let mut raw_containers: Vec<_> = raw_containers.into_iter().collect();
+        raw_containers.sort_by_key(|(_, raw)| classify(raw, &self.config, &self.targets) != Classification::Target);
+
+        // containers show up before being attached to the network: they are only taken in once settled
+        let pending = std::mem::take(&mut self.pending);
+        raw_containers.retain(|(id, raw)| {
+            let classification = classify(raw, &self.config, &self.targets);
+            let polls = match pending.get(id) {
+                Some((previous, polls)) if *previous == classification => polls + 1,
+                _ => 1,
+            };
+            if polls < self.config.debounce_polls {
+                self.pending.insert(id.clone(), (classification, polls));
+            }
+            polls >= self.config.debounce_polls
+        });
+
+        for (id, new) in raw_containers {
+            let (network, ip, ipv6) = self.config.locate(&new);
+            let service = new.labels.get(self.config.service_label_key()).cloned();
+            let classification = classify(&new, &self.config, &self.targets);
+
+            // excluded containers never carry a flag, so they are not routed even once known
+            let excluded = classification == Classification::Excluded;
+            let flag = new.labels.get(&self.config.label_key).filter(|value| !excluded && self.config.flags(value));
+            let aliases = flag.filter(|_| self.config.label_value.is_none()).and_then(|flag| StringVec::parse(flag));
+            // without `require_flag`, every container of the networks but the targets stands as flagged
+            let flag = flag.cloned().or_else(|| (classification == Classification::Flagged).then(String::new));
+
+            let c = Container {
+                id: id.clone(),
+                name: new.name.clone(),
+                service: service.clone(),
+                network: network.cloned(),
+                ip: ip.cloned(),
+                ipv6: ipv6.cloned(),
+                flag,
+                aliases: aliases.or_else(|| self.label_dependencies(&new)),
+                created: new.created,
+                target: (classification == Classification::Target).then(|| self.config.target_key(&new).cloned()).flatten(),
+                alias: new.labels.get(ALIAS_LABEL).filter(|alias| is_hostname(alias)).cloned(),
+            };
+
+            let container = c.clone();
+
+            events.push(match classification {
+                Classification::Excluded => StackEvents::Excluded(container),
+                Classification::Target => {
+                    let known = Self::routable(&new_containers, &container);
+                    let address = c.address().expect("targets have an ip");
+                    let target = c.target.clone().expect("targets have a target key");
+                    StackEvents::Target(container, known, target, address)
+                }
+                Classification::Flagged => StackEvents::New(container),
+                Classification::NoFlag => StackEvents::NoFlag(container),
+                Classification::Outside => StackEvents::OutsideNetwork(container),
+            });
+
+            new_containers.insert(id.clone(), c);
+        }
+
+        // while a target restarts, the old and new containers coexist: only the newest one is routed to
+        events.retain(|event| match event {
+            StackEvents::Target(target, ..) => !new_containers.values().any(|other| {
+                other.target == target.target && other.address().is_some() && other.created > target.created
+            }),
+            _ => true,
+        });
+
+        self.map = Some(new_containers);
+
+        events
+    }
+
+    /// Hostnames given more than one ip within a single hosts file, over every block about to be written to it:
+    /// those routing to the targets, the mesh and the targets' own. Each comes with the containers claiming it,
+    /// so that one target per network is no conflict but two containers sharing an alias are.
+    fn hostname_conflicts(&self) -> Vec<(String, Vec<String>)> {
+        let map = self.map.as_ref().expect("actualized");
+        let flagged: Vec<_> = map.values().filter(|item| item.flag().is_some() && item.ip().is_some()).collect();
+        // the hosts file written, the hostname, its ip and the container it stands for
+        let mut claims: Vec<(&str, String, &str, &str)> = vec![];
+
+        for container in &flagged {
+            for (service, address) in self.target_ip.iter().filter(|(service, address)| self.reaches(container, service, address)) {
+                claims.extend(hostnames(&self.config, container).into_iter().map(|hostname| (container.id.as_str(), hostname, address.ip.as_str(), container.hash())));
+                if self.config.route_into_target {
+                    let target = map.values().find(|item| item.target.as_deref() == Some(service.as_str()) && item.address().as_ref() == Some(address));
+                    if let (Some(target), Some(alias), Some(ip)) = (target, container.alias(), container.ip()) {
+                        claims.push((target.id.as_str(), alias, ip, container.hash()));
+                    }
+                }
+            }
+            if self.config.mesh {
+                for peer in flagged.iter().filter(|peer| peer.id != container.id && peer.network == container.network) {
+                    if let (Some(alias), Some(ip)) = (peer.alias(), peer.ip()) {
+                        claims.push((container.id.as_str(), alias, ip, peer.hash()));
+                    }
+                }
+            }
+        }
+
+        let mut files: HashMap<(&str, String), Vec<(&str, &str)>> = HashMap::new();
+        for (file, hostname, ip, container) in claims {
+            files.entry((file, hostname)).or_default().push((ip, container));
+        }
+
+        let mut conflicts: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+        for ((_, hostname), claimed) in files {
+            if claimed.iter().map(|(ip, _)| ip).collect::<HashSet<_>>().len() > 1 {
+                conflicts.entry(hostname).or_default().extend(claimed.into_iter().map(|(_, container)| container));
+            }
+        }
+        conflicts.into_iter().map(|(hostname, containers)| (hostname, containers.into_iter().map(ToString::to_string).collect())).collect()
+    }
+
+    fn label_dependencies(&self, raw: &RawContainer) -> Option<StringVec> {
+        self.config.dependencies_from_labels.then(|| {
+            raw.labels.get(self.config.dependencies_label()).map_or_else(StringVec::default, |value| StringVec::depends_on(value))
+        })
+    }
+
+    /// The flagged containers able to reach `target`, that is sharing its network.
+    fn routable(containers: &HashMap<String, Container>, target: &Container) -> Vec<Container> {
+        let mut routable: Vec<_> = containers.values().filter(|item| {
+            item.flag().is_some() && item.ip().is_some() && item.network == target.network
+        }).cloned().collect();
+        routable.sort_by(|left, right| left.id.cmp(&right.id));
+        routable
+    }
+
+    /// With `network = "auto"`, settles on the network of the newest target, preferring user-defined networks when it has several.
+    /// Picks again once the target shows up in another network only, clearing what was written for the previous one.
+    /// Answers whether the network is known, nothing being routed until it is.
+    async fn pick_network<D: Docker, W: LogWrite>(&mut self, docker: &D, f: &mut W, containers: &HashMap<String, RawContainer>) -> Result<bool> {
+        if !self.auto_network {
+            return Ok(true);
+        }
+        let picked = !self.config.auto_network();
+
+        let Some(target) = containers.values()
+            .filter(|raw| self.config.target_key(raw).is_some_and(|key| self.targets.matches(key)))
+            .max_by_key(|raw| raw.created) else {
+            // a target gone keeps the network picked, where the next one most likely shows up
+            if !picked {
+                text(f, &self.config, Level::Debug, format_args!("no target found yet to pick a network from"))?;
+            }
+            return Ok(picked);
+        };
+
+        let mut candidates: Vec<&String> = target.networks.iter().filter(|(_, ip)| !ip.is_empty()).map(|(network, _)| network).collect();
+        if picked && candidates.iter().any(|network| self.config.network.contains(network)) {
+            return Ok(true);
+        }
+        candidates.sort();
+        let defined = docker.list_networks().await?;
+        let Some(network) = candidates.iter().find(|network| defined.contains(network)).or(candidates.first()) else {
+            return Ok(picked);
+        };
+
+        if picked {
+            // blocks written under the previous network would never be trimmed once routing in the new one
+            self.clear(docker, f).await?;
+            self.forget();
+        }
+        text(f, &self.config, Level::Notice, format_args!("picked network {}, the one of target container {}", network, &target.id[..target.id.len().min(16)]))?;
+        json(f, &self.config, Level::Notice, json!({"event": "network_picked", "network": network, "target": target.id}))?;
+        self.config.network = vec![network.to_string()];
+        Ok(true)
+    }
+
+    /// Whether `container` shares the network of the target container of `service` found at `address`.
+    fn reaches(&self, container: &Container, service: &str, address: &Address) -> bool {
+        self.map.iter().flat_map(HashMap::values).any(|target| {
+            target.target.as_deref() == Some(service) && target.address().as_ref() == Some(address) && target.network == container.network
+        })
+    }
+}
+
+/// A misspelled network only ever shows as containers outside of it, so it is called out once at startup.
+async fn check_networks<D: Docker, W: LogWrite>(docker: &D, f: &mut W, config: &Config) -> Result<()> {
+    for network in config.network.iter().filter(|_| !config.auto_network()) {
+        match docker.network_exists(network).await {
+            Ok(true) => {}
+            Ok(false) => {
+                text(f, config, Level::Warn, format_args!("network {} does not exist, no container will be routed until it is created", network))?;
+                json(f, config, Level::Warn, json!({"event": "missing_network", "network": network}))?;
+            }
+            Err(e) => {
+                text(f, config, Level::Warn, format_args!("could not check that network {} exists: {}", network, e))?;
+                json(f, config, Level::Warn, json!({"event": "network_check_failed", "network": network, "error": e.to_string()}))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Polls once and prints every container found with how it is classified, writing nothing in any of them.
+/// The networks are polled as well, so that containers missing the flag show up too.
+///
+/// # Errors
+///
+/// Fails when docker cannot be polled or `f` cannot be written to.
+pub async fn list<D: Docker, W: Write>(docker: D, mut f: W, config: Config) -> Result<()> {
+    let mut stack = CurrentStack::new(Config { debounce_polls: 0, ..config });
+    let mut filters = stack.config.poll_filters();
+    for network in stack.config.network.iter().filter(|_| !stack.config.auto_network()) {
+        let filter = PollFilter::Network(network.clone());
+        if !filters.contains(&filter) {
+            filters.push(filter);
+        }
+    }
+
+    let containers = docker.poll(&filters).await?;
+    if !stack.pick_network(&docker, &mut std::io::sink(), &containers).await? {
+        writeln!(f, "no target found to pick a network from")?;
+        return Ok(());
+    }
+
+    let mut rows: Vec<_> = stack.actualize(containers).into_iter().filter_map(|event| match event {
+        StackEvents::Target(container, ..) => Some(("target", container)),
+        StackEvents::New(container) => Some(("flagged", container)),
+        StackEvents::NoFlag(container) => Some(("ignored-noflag", container)),
+        StackEvents::OutsideNetwork(container) => Some(("ignored-network", container)),
+        StackEvents::Excluded(container) => Some(("excluded", container)),
+        StackEvents::Gone(_) | StackEvents::TargetGone(..) | StackEvents::LostNetwork(_) => None,
+    }).collect();
+    rows.sort_by(|(left, left_container), (right, right_container)| (left, &left_container.id).cmp(&(right, &right_container.id)));
+
+    writeln!(f, "{:<16}  {:<15}  {:<20}  {:<24}  IP", "CONTAINER", "CLASSIFICATION", "SERVICE", "NAME")?;
+    for (classification, container) in rows {
+        writeln!(
+            f,
+            "{:<16}  {:<15}  {:<20}  {:<24}  {}",
+            container.hash(),
+            classification,
+            container.service().unwrap_or("-"),
+            container.name().unwrap_or_else(|| "-".into()),
+            container.ip().unwrap_or("-")
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The source of a config given once, which never changes.
+struct NoReload;
+
+impl ConfigSource for NoReload {
+    /// Never asked for as it never changes, and an error rather than a panic if it were: the loop keeps its config.
+    fn load(&mut self) -> Result<Config> {
+        Err(BusinessError::Other("the config was given once and cannot be reloaded".to_string()))
+    }
+
+    fn changed(&self) -> bool {
+        false
+    }
+}
+
+/// Polls docker forever, keeping the hosts files of flagged containers pointing at the target.
+///
+/// # Errors
+///
+/// Fails when the signal handlers cannot be installed, when polling fails more than `max_poll_errors` times in a row,
+/// or when the log cannot be written to.
+pub async fn event_loop<D: Docker, W: Write>(docker: D, write: W, config: Config) -> Result<()> {
+    run_until(docker, Unleveled(write), config, NoReload, || true).await
+}
+
+/// Same as [`event_loop`], reloading the config whenever `source` reports it changed.
+///
+/// # Errors
+///
+/// Same as [`event_loop`].
+pub async fn event_loop_with_source<D: Docker, W: LogWrite, S: ConfigSource>(docker: D, write: W, config: Config, source: S) -> Result<()> {
+    run_until(docker, write, config, source, || true).await
+}
+
+/// Runs the loop of [`event_loop`] while `keep_going` answers true after each poll, so tests can bound it.
+async fn run_until<D: Docker, W: LogWrite, S: ConfigSource, F: FnMut() -> bool>(
+    mut docker: D,
+    mut write: W,
+    config: Config,
+    mut source: S,
+    mut keep_going: F,
+) -> Result<()> {
+    let mut last_tick = Instant::now();
+    let mut stack = CurrentStack::new(config);
+    let mut tick_rate = stack.config.poll_interval();
+    let mut poll_errors = 0;
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+    let mut hangup = signal(SignalKind::hangup())?;
+    banner(&mut write, &stack.config)?;
+    if let Some(connection) = docker.connection() {
+        text(&mut write, &stack.config, Level::Info, format_args!("connecting to {}", connection))?;
+        json(&mut write, &stack.config, Level::Info, json!({"event": "connecting", "connection": connection}))?;
+    }
+    check_networks(&docker, &mut write, &stack.config).await?;
+    let mut metrics_server = match &stack.config.metrics_addr {
+        Some(addr) => serve_metrics(&mut write, &stack.config, addr, &stack.metrics)?,
+        None => None,
+    };
+    loop {
+        if let Some(e) = metrics_stopped(&mut metrics_server).await {
+            text(&mut write, &stack.config, Level::Warn, format_args!("metrics endpoint stopped: {}", e))?;
+            json(&mut write, &stack.config, Level::Warn, json!({"event": "metrics_stopped", "error": e}))?;
+        }
+        if source.changed() {
+            stack.reload(&docker, &mut write, source.load()).await?;
+            tick_rate = stack.config.poll_interval();
+        }
+
+        match stack.loop_once(&mut docker, &mut write).await {
+            Err(e) if poll_errors < stack.config.max_poll_errors() => {
+                poll_errors += 1;
+                text(&mut write, &stack.config, Level::Warn, format_args!("{}, tolerating it ({}/{} in a row)", e, poll_errors, stack.config.max_poll_errors()))?;
+                json(&mut write, &stack.config, Level::Warn, json!({"event": "poll_failed", "error": e.to_string(), "attempt": poll_errors}))?;
+            }
+            Err(e) => {
+                text(&mut write, &stack.config, Level::Error, format_args!("{}", e))?;
+                json(&mut write, &stack.config, Level::Error, json!({"event": "error", "error": e.to_string()}))?;
+                return Err(e);
+            }
+            Ok(_) => {
+                poll_errors = 0;
+
+                if let Some(path) = &stack.config.health_file {
+                    if let Err(e) = touch(path) {
+                        text(&mut write, &stack.config, Level::Warn, format_args!("could not touch health file {}: {}", path, e))?;
+                        json(&mut write, &stack.config, Level::Warn, json!({"event": "health_file_failed", "path": path, "error": e.to_string()}))?;
+                    }
+                }
+
+                // containers may start in any order, so a single poll can miss the target: report it rather than exit 0
+                if stack.config.oneshot {
+                    if stack.target_ip.is_empty() {
+                        return Err(StackError::NoTarget(stack.config.target.join(", ")).into());
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        if !keep_going() {
+            return Ok(());
+        }
+
+        let caught_up = tick_rate > last_tick.elapsed();
+        let delay = tick_rate
+            .checked_sub(last_tick.elapsed())
+            .unwrap_or(Duration::from_millis(0));
+
+        let shutting_down = tokio::select! {
+            biased;
+            () = &mut shutdown => true,
+            _ = hangup.recv() => {
+                stack.dump_state(&mut write)?;
+                false
+            }
+            waited = docker.wait(delay) => {
+                waited?;
+                false
+            }
+        };
+
+        if let Some(reason) = docker.take_events_lost() {
+            text(&mut write, &stack.config, Level::Warn, format_args!("{}, falling back to polling", reason))?;
+            json(&mut write, &stack.config, Level::Warn, json!({"event": "events_lost", "reason": reason}))?;
+        }
+
+        if shutting_down {
+            text(&mut write, &stack.config, Level::Info, format_args!("shutting down"))?;
+            json(&mut write, &stack.config, Level::Info, json!({"event": "shutdown"}))?;
+            return stack.clear(&docker, &mut write).await;
+        }
+
+        if caught_up {
+            last_tick = Instant::now();
+        }
+    }
+}
+
+impl CurrentStack {
+    /// Takes in the config read again from disk, starting over when `restart_on_change` asks for it.
+    async fn reload<D: Docker, W: LogWrite>(&mut self, docker: &D, f: &mut W, loaded: Result<Config>) -> Result<()> {
+        match loaded {
+            Ok(config) if config.restart_on_change => {
+                text(f, &config, Level::Info, format_args!("config changed on disk, restarting with it"))?;
+                json(f, &config, Level::Info, json!({"event": "config_restarted"}))?;
+                self.clear(docker, f).await?;
+                let metrics = Arc::clone(&self.metrics);
+                *self = Self::new(config);
+                self.metrics = metrics;
+                banner(f, &self.config)?;
+            }
+            Ok(mut config) => {
+                if config.auto_network() && self.auto_network {
+                    // keeps the network already picked, rather than seeing it as a change
+                    config.network.clone_from(&self.config.network);
+                }
+                if !config.same_guards(&self.config) {
+                    // blocks written under the previous guards would never be trimmed by the new ones
+                    self.clear(docker, f).await?;
+                    self.forget();
+                }
+                self.targets = config.targets()?;
+                self.warnings = config.warnings();
+                self.config = config;
+                text(f, &self.config, Level::Info, format_args!("config changed on disk, reloading it"))?;
+                json(f, &self.config, Level::Info, json!({"event": "config_reloaded"}))?;
+            }
+            Err(e) => {
+                text(f, &self.config, Level::Warn, format_args!("could not reload config, keeping the previous one: {}", e))?;
+                json(f, &self.config, Level::Warn, json!({"event": "config_reload_failed", "error": e.to_string()}))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn banner<W: LogWrite>(f: &mut W, config: &Config) -> Result<()> {
+    if config.log_format == LogFormat::Json {
+        return json_line(f, config, Level::Info, json!({
+            "event": "startup",
+            "version": env!("CARGO_PKG_VERSION"),
+            "network": config.network,
+            "label": config.label_key,
+            "target": config.target,
+            "dependencies": config.dependencies,
+        }));
+    }
+
+    log_line(f, config, Level::Info, format_args!("{} version {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))?;
+    log_line(
+        f,
+        config,
+        Level::Info,
+        format_args!(
+            "Looking for containers in network {} with label {} to be routed via service «{}» as {}",
+            config.network.join(", "), config.label_key, config.target.join(", "), config.dependencies
+        ),
+    )
+}
+
+#[cfg(feature = "metrics")]
+fn serve_metrics<W: LogWrite>(f: &mut W, config: &Config, addr: &str, metrics: &Arc<Metrics>) -> Result<Option<JoinHandle<Result<()>>>> {
+    text(f, config, Level::Info, format_args!("serving metrics on http://{}/metrics", addr))?;
+    Ok(Some(tokio::spawn(metrics::serve(addr.to_string(), Arc::clone(metrics)))))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_metrics<W: LogWrite>(f: &mut W, config: &Config, _: &str, _: &Arc<Metrics>) -> Result<Option<JoinHandle<Result<()>>>> {
+    text(f, config, Level::Warn, format_args!("metrics_addr is set but {} was built without the metrics feature", env!("CARGO_PKG_NAME")))?;
+    Ok(None)
+}
+
+/// Why the metrics endpoint stopped, once it has, for the loop to log.
+async fn metrics_stopped(server: &mut Option<JoinHandle<Result<()>>>) -> Option<String> {
+    if !server.as_ref().is_some_and(JoinHandle::is_finished) {
+        return None;
+    }
+    match server.take()?.await {
+        Ok(served) => served.err().map(|e| e.to_string()),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Creates `path` if needed and bumps its mtime, so a healthcheck can tell the loop is alive.
+fn touch(path: &str) -> std::io::Result<()> {
+    std::fs::OpenOptions::new().create(true).append(true).open(path)?.set_modified(SystemTime::now())
+}
+
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+    })
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Guard<'a> {
+    pub prefix: &'a str,
+    pub network: &'a str,
+    pub target: &'a str,
+    /// Opens the block with a comment telling who wrote it and when.
+    pub annotate: bool,
+    /// Fixed hostname to ip entries closing the block.
+    pub extra_hosts: Option<&'a BTreeMap<String, String>>,
+}
+
+/// Starts the annotation comment, which [`hosts_update`] leaves out of the comparison since it is dated.
+const ANNOTATION: &str = concat!("# managed by ", env!("CARGO_PKG_NAME"));
+
+impl Guard<'_> {
+    fn lines(&self) -> (String, String) {
+        const PACKAGE: &str = env!("CARGO_PKG_NAME");
+
+        (
+            format!("{} open {} {} {}\n", self.prefix, PACKAGE, self.network, self.target),
+            format!("{} close {} {} {}\n", self.prefix, PACKAGE, self.network, self.target),
+        )
+    }
+}
+
+#[must_use]
+pub fn clear_host_file(file: String, guard: &Guard) -> String {
+    let (open_guard, close_guard) = guard.lines();
+
+    trim_host_from_guards(file, &open_guard, &close_guard)
+}
+
+#[must_use]
+pub fn update_host_file(file: String, lines: &[String], guard: &Guard, host: &str, host_v6: Option<&str>) -> String {
+    let (open_guard, close_guard) = guard.lines();
+    let newline = if file.split_inclusive('\n').next().is_some_and(|line| line.ends_with("\r\n")) { "\r\n" } else { "\n" };
+
+    let mut content = trim_host_from_guards(file, &open_guard, &close_guard);
+    // the open guard would otherwise be glued to a last line lacking its newline
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push_str(newline);
+    }
+
+    format!("{}{}", content, guard_block(lines, guard, host, host_v6).replace('\n', newline))
+}
+
+/// Tells whether writing `new` over `old` would change anything.
+#[must_use]
+pub fn hosts_update(old: &str, new: &str) -> HostsUpdate {
+    fn content(file: &str) -> impl Iterator<Item = &str> {
+        file.split_inclusive('\n').filter(|line| !line.starts_with(ANNOTATION))
+    }
+
+    if content(old).eq(content(new)) {
+        HostsUpdate::Unchanged
+    } else {
+        HostsUpdate::Changed(hosts_diff(old, new))
+    }
+}
+
+/// Lists the lines `new` drops from `old` as `-line`, then the ones it adds as `+line`.
+#[must_use]
+pub fn hosts_diff(old: &str, new: &str) -> String {
+    fn missing<'a>(from: &'a str, other: &str) -> Vec<&'a str> {
+        let mut left: HashMap<&str, usize> = HashMap::new();
+        for line in other.lines() {
+            *left.entry(line).or_default() += 1;
+        }
+
+        from.lines()
+            .filter(|line| match left.get_mut(line) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    let removed = missing(old, new).into_iter().map(|line| format!("-{}\n", line));
+    let added = missing(new, old).into_iter().map(|line| format!("+{}\n", line));
+
+    removed.chain(added).collect()
+}
+
+#[must_use]
+pub fn guard_block(lines: &[String], guard: &Guard, host: &str, host_v6: Option<&str>) -> String {
+    let (open_guard, close_guard) = guard.lines();
+    let hosts = [Some(host), host_v6];
+    let mut seen = HashSet::new();
+    let annotation = if guard.annotate {
+        format!("{}, target={}, updated={}\n", ANNOTATION, guard.target, humantime::format_rfc3339_seconds(SystemTime::now()))
+    } else {
+        String::new()
+    };
+
+    let extra_hosts: String = guard.extra_hosts.into_iter().flatten().map(|(hostname, ip)| format!("{}\t{}\n", ip, hostname)).collect();
+
+    format!(
+        "{open_guard}{annotation}{}{extra_hosts}{close_guard}",
+        lines.iter()
+            .filter(|str| seen.insert(*str))
+            .flat_map(|str| hosts.iter().flatten().map(move |host| format!("{}\t{}\n", host, str)))
+            .collect::<Vec<String>>().join("")
+    )
+}
+
+/// Appends `suffix` to every non-empty line that does not already end with it.
+#[must_use]
+pub fn suffixed(lines: &[String], suffix: Option<&str>) -> Vec<String> {
+    lines
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| match suffix {
+            Some(suffix) if !line.ends_with(suffix) => format!("{}{}", line, suffix),
+            _ => line.clone(),
+        })
+        .collect()
+}
+
+/// Packs `content` as the single file described by `header`, as the docker upload expects.
+///
+/// # Errors
+///
+/// Fails when the archive cannot be built.
+pub fn hosts_archive(header: &tar::Header, content: &str) -> Result<Vec<u8>> {
+    let mut header = header.clone();
+    header.set_size(content.len() as u64);
+    header.set_cksum();
+
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append(&header, content.as_bytes())?;
+    Ok(builder.into_inner()?)
+}
+
+/// Finds the file named `name` in an archive downloaded from a container, skipping any directory entry.
+///
+/// # Errors
+///
+/// Fails when the archive is malformed or the file is not valid UTF-8.
+pub fn read_hosts_archive(archive: &[u8], name: &str) -> Result<Option<(String, tar::Header)>> {
+    let mut archive = tar::Archive::new(archive);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() && entry.path()?.ends_with(name) {
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            return Ok(Some((content, entry.header().clone())));
+        }
+    }
+
+    Ok(None)
+}
+
+#[must_use]
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r#"'\''"#))
+}
+
+/// Removes every block between `open_guard` and `close_guard`, so that blocks appended repeatedly are all cleaned up.
+/// Stray guards, a close with no open block or an open followed by another, are dropped along the way.
+/// Guards are matched whatever their line ending or trailing spaces; a block left open at the end is kept as is.
+#[must_use]
+pub fn trim_host_from_guards(file: String, open_guard: &str, close_guard: &str) -> String {
+    fn is_guard(line: &str, guard: &str) -> bool {
+        line.trim_end() == guard.trim_end()
+    }
+
+    let mut trimmed = String::with_capacity(file.len());
+    // the open guard and the lines read since
+    let mut block: Option<(&str, String)> = None;
+
+    for line in file.split_inclusive('\n') {
+        match &mut block {
+            None if is_guard(line, open_guard) => block = Some((line, String::new())),
+            None if is_guard(line, close_guard) => {}
+            None => trimmed.push_str(line),
+            Some(_) if is_guard(line, close_guard) => block = None,
+            Some((open, held)) if is_guard(line, open_guard) => {
+                trimmed.push_str(held);
+                held.clear();
+                *open = line;
+            }
+            Some((_, held)) => held.push_str(line),
+        }
+    }
+
+    if let Some((open, held)) = block {
+        trimmed.push_str(open);
+        trimmed.push_str(&held);
+    }
+    trimmed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::business::{classify, event_loop, run_until, NoReload, BusinessError, Classification, TickReport, trim_host_from_guards, with_backoff, Address, Config, ConfigError, ConfigSource, Container, CurrentStack, Docker, DockerError, Guard, HostsUpdate, LogFormat, PollFilter, RawContainer, TargetMatch, Verbosity, Result, StackError, StringVec};
+    use async_trait::async_trait;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    const PACKAGE: &str = env!("CARGO_PKG_NAME");
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct Update {
+        id: String,
+        dependencies: Vec<String>,
+        network: String,
+        target: String,
+        ip: String,
+    }
+
+    /// Answers `poll` from a script, one entry per call, and records every hosts update.
+    #[derive(Default)]
+    struct MockDocker {
+        polls: Mutex<VecDeque<HashMap<String, RawContainer>>>,
+        updates: Mutex<Vec<Update>>,
+        clears: Mutex<Vec<(String, String)>>,
+        hosts: Mutex<HashMap<String, String>>,
+        writes: Mutex<usize>,
+        inspected: Mutex<HashMap<String, RawContainer>>,
+        broken: HashSet<String>,
+        failing_polls: Mutex<usize>,
+        missing_networks: HashSet<String>,
+        networks: Vec<String>,
+    }
+
+    impl MockDocker {
+        fn new(polls: Vec<Vec<RawContainer>>) -> Self {
+            Self {
+                polls: Mutex::new(polls.into_iter().map(|poll| {
+                    poll.into_iter().map(|raw| (raw.id.clone(), raw)).collect()
+                }).collect()),
+                ..Default::default()
+            }
+        }
+
+        fn updated(&self) -> Vec<(String, String)> {
+            self.updates.lock().unwrap().iter().map(|update| (update.id.clone(), update.ip.clone())).collect()
+        }
+    }
+
+    #[async_trait]
+    impl Docker for MockDocker {
+        async fn poll(&self, _: &[PollFilter]) -> Result<HashMap<String, RawContainer>> {
+            let mut failing = self.failing_polls.lock().unwrap();
+            if *failing > 0 {
+                *failing -= 1;
+                return Err("daemon unavailable".into());
+            }
+            Ok(self.polls.lock().unwrap().pop_front().unwrap_or_default())
+        }
+
+        async fn inspect(&self, id: &str) -> Result<Option<RawContainer>> {
+            Ok(self.inspected.lock().unwrap().get(id).cloned())
+        }
+
+        async fn reconnect(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn network_exists(&self, name: &str) -> Result<bool> {
+            Ok(!self.missing_networks.contains(name))
+        }
+
+        async fn list_networks(&self) -> Result<Vec<String>> {
+            Ok(self.networks.clone())
+        }
+
+        async fn update_hosts_for(&self, container: Container, dependencies: &[String], guard: &Guard<'_>, host: &Address) -> Result<HostsUpdate> {
+            if self.broken.contains(&container.id()) {
+                return Err(DockerError::NoHost(container.id()).into());
+            }
+            self.updates.lock().unwrap().push(Update {
+                id: container.id(),
+                dependencies: dependencies.to_vec(),
+                network: guard.network.to_string(),
+                target: guard.target.to_string(),
+                ip: host.ip.clone(),
+            });
+
+            let mut hosts = self.hosts.lock().unwrap();
+            let old = hosts.get(&container.id()).cloned().unwrap_or_default();
+            let new = super::update_host_file(old.clone(), dependencies, guard, &host.ip, host.ipv6.as_deref());
+            let update = super::hosts_update(&old, &new);
+            if update != HostsUpdate::Unchanged {
+                *self.writes.lock().unwrap() += 1;
+                hosts.insert(container.id(), new);
+            }
+            Ok(update)
+        }
+
+        async fn clear_hosts_for(&self, container: Container, guard: &Guard<'_>) -> Result<()> {
+            self.clears.lock().unwrap().push((container.id(), guard.network.to_string()));
+            Ok(())
+        }
+    }
+
+    /// Hands over its config once, on the second check.
+    struct Reload {
+        checks: std::cell::Cell<usize>,
+        config: Option<Config>,
+    }
+
+    impl ConfigSource for Reload {
+        fn load(&mut self) -> Result<Config> {
+            Ok(self.config.take().expect("loaded once"))
+        }
+
+        fn changed(&self) -> bool {
+            self.checks.set(self.checks.get() + 1);
+            self.checks.get() > 1 && self.config.is_some()
+        }
+    }
+
+    const GUARD: Guard = Guard {
+        prefix: "###",
+        network: "network",
+        target: "target",
+        annotate: false,
+        extra_hosts: None,
+    };
+
+    fn raw(id: &str, service: &str, ip: &str, flagged: bool) -> RawContainer {
+        let mut labels = HashMap::from([("com.docker.compose.service".to_string(), service.to_string())]);
+        if flagged {
+            labels.insert("flag".into(), "true".into());
+        }
+
+        RawContainer {
+            id: id.into(),
+            name: Some(service.into()),
+            networks: HashMap::from([("network".to_string(), ip.to_string())]),
+            ipv6_networks: HashMap::new(),
+            labels,
+            created: None,
+        }
+    }
+
+    fn stack() -> CurrentStack {
+        CurrentStack::new(toml::from_str::<Config>(r#"
+network = "network"
+label_key = "flag"
+target = "web"
+dependencies = ["api"]
+timestamps = false
+"#).unwrap())
+    }
+
+    #[test]
+    pub fn test_remove() {
+        let host_file = format!("
+1.1.1.1 toto
+### open guard guard
+### close guard guard
+1.2.3.4 titi
+");
+
+        let str = "
+1.1.1.1 toto
+1.2.3.4 titi
+".to_string();
+        assert_eq!(
+            trim_host_from_guards(host_file, "### open guard guard\n", "### close guard guard\n"),
+            str
+        )
+    }
+
+    #[test]
+    pub fn test_remove_duplicate_open_guard() {
+        let host_file = "1.1.1.1 toto
+### open guard guard
+### open guard guard
+1.1.1.1 stale
+### close guard guard
+1.2.3.4 titi
+### close guard guard
+".to_string();
+
+        let trimmed = trim_host_from_guards(host_file, "### open guard guard\n", "### close guard guard\n");
+        assert_eq!(trimmed, "1.1.1.1 toto
+1.2.3.4 titi
+");
+
+        let guard = Guard { network: "guard", target: "guard", ..GUARD };
+        let updated = super::update_host_file(trimmed, &["web".into()], &guard, "1.1.1.1", None);
+        assert_eq!(updated.matches(" open ").count(), 1, "{}", updated);
+        assert_eq!(updated.matches(" close ").count(), 1, "{}", updated);
+        assert!(updated.contains("1.2.3.4 titi\n"));
+    }
+
+    #[test]
+    pub fn crlf_hosts_file_gets_a_single_block() {
+        let lines = vec!["web".into()];
+        let host_file = "127.0.0.1\tlocalhost\r\n::1\tlocalhost \r\n".to_string();
+
+        let once = super::update_host_file(host_file, &lines, &GUARD, "1.1.1.1", None);
+        let twice = super::update_host_file(once.clone(), &lines, &GUARD, "1.1.1.1", None);
+
+        assert_eq!(once, format!("127.0.0.1\tlocalhost\r\n::1\tlocalhost \r\n### open {} network target\r\n1.1.1.1\tweb\r\n### close {} network target\r\n", PACKAGE, PACKAGE));
+        assert_eq!(twice, once);
+    }
+
+    #[test]
+    pub fn crlf_guard_blocks_are_replaced() {
+        let lines = vec!["web".into()];
+        let stale = format!("### open {} network target \r\n1.1.1.9\tweb\r\n### close {} network target\r\n", PACKAGE, PACKAGE);
+        let host_file = format!("127.0.0.1\tlocalhost\r\n{}{}", stale, stale);
+
+        let updated = super::update_host_file(host_file, &lines, &GUARD, "1.1.1.1", None);
+
+        assert_eq!(updated, format!("127.0.0.1\tlocalhost\r\n### open {} network target\r\n1.1.1.1\tweb\r\n### close {} network target\r\n", PACKAGE, PACKAGE));
+    }
+
+    #[test]
+    pub fn test_remove_at_end_of_file() {
+        let host_file = "1.1.1.1 toto
+1.2.3.4 titi
+### open guard guard
+1.1.1.1 stale
+### close guard guard".to_string();
+
+        assert_eq!(
+            trim_host_from_guards(host_file, "### open guard guard\n", "### close guard guard\n"),
+            "1.1.1.1 toto\n1.2.3.4 titi\n"
+        )
+    }
+
+    #[test]
+    pub fn update_keeps_a_last_line_without_newline() {
+        let lines = vec!["web".into()];
+        let host_file = super::update_host_file("127.0.0.1\tlocalhost\n1.2.3.4\ttiti".into(), &lines, &GUARD, "1.1.1.1", None);
+
+        assert_eq!(host_file, format!("127.0.0.1\tlocalhost\n1.2.3.4\ttiti\n### open {} network target\n1.1.1.1\tweb\n### close {} network target\n", PACKAGE, PACKAGE));
+        assert_eq!(super::clear_host_file(host_file, &GUARD), "127.0.0.1\tlocalhost\n1.2.3.4\ttiti\n");
+    }
+
+    #[test]
+    pub fn test_remove_without_close_guard() {
+        let host_file = "1.1.1.1 toto
+### open guard guard
+1.2.3.4 titi
+".to_string();
+
+        assert_eq!(
+            trim_host_from_guards(host_file.clone(), "### open guard guard\n", "### close guard guard\n"),
+            host_file
+        )
+    }
+
+    #[test]
+    pub fn hosts_archive_keeps_the_original_header() {
+        let mut header = tar::Header::new_gnu();
+        header.set_path("hosts").unwrap();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+
+        let archive = super::hosts_archive(&header, "1.1.1.1\tweb\n").unwrap();
+        let mut archive = tar::Archive::new(&archive[..]);
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+
+        assert_eq!(content, "1.1.1.1\tweb\n");
+        assert_eq!(entry.path().unwrap().to_str(), Some("hosts"));
+        assert_eq!(entry.header().mode().unwrap(), 0o644);
+    }
+
+    #[test]
+    pub fn read_hosts_archive_skips_the_directory_entry() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut directory = tar::Header::new_gnu();
+        directory.set_path("etc/").unwrap();
+        directory.set_entry_type(tar::EntryType::Directory);
+        directory.set_size(0);
+        directory.set_cksum();
+        builder.append(&directory, &[][..]).unwrap();
+        let mut file = tar::Header::new_gnu();
+        file.set_path("etc/hosts").unwrap();
+        file.set_size(12);
+        file.set_cksum();
+        builder.append(&file, &b"1.1.1.1\tweb\n"[..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let (content, header) = super::read_hosts_archive(&archive, "hosts").unwrap().unwrap();
+
+        assert_eq!(content, "1.1.1.1\tweb\n");
+        assert_eq!(header.path().unwrap().to_str(), Some("etc/hosts"));
+    }
+
+    #[test]
+    pub fn hosts_file_round_trips_verbatim() {
+        let original = "127.0.0.1\tlocalhost\n::1\tip6-localhost ip6-loopback\n10.0.0.2\tliteral\\tname\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_path("hosts").unwrap();
+
+        let downloaded = super::hosts_archive(&header, original).unwrap();
+        let (content, header) = super::read_hosts_archive(&downloaded, "hosts").unwrap().unwrap();
+        assert_eq!(content, original);
+
+        let updated = super::update_host_file(content, &["api".into()], &GUARD, "1.1.1.1", None);
+        let uploaded = super::hosts_archive(&header, &updated).unwrap();
+        let (content, _) = super::read_hosts_archive(&uploaded, "hosts").unwrap().unwrap();
+
+        assert_eq!(super::clear_host_file(content, &GUARD), original);
+    }
+
+    #[test]
+    pub fn read_hosts_archive_without_hosts_file() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut file = tar::Header::new_gnu();
+        file.set_path("hostname").unwrap();
+        file.set_size(3);
+        file.set_cksum();
+        builder.append(&file, &b"web"[..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        assert!(super::read_hosts_archive(&archive, "hosts").unwrap().is_none());
+    }
+
+    #[test]
+    pub fn hosts_diff_only_shows_the_changed_lines() {
+        let old = format!("127.0.0.1\tlocalhost
+### open {} network target
+1.1.1.1\tapi
+1.1.1.1\tweb
+### close {} network target
+", PACKAGE, PACKAGE);
+        let new = super::update_host_file(old.clone(), &["api".into(), "db".into()], &GUARD, "1.1.1.3", None);
+
+        assert_eq!(super::hosts_diff(&old, &new), "-1.1.1.1\tapi
+-1.1.1.1\tweb
++1.1.1.3\tapi
++1.1.1.3\tdb
+");
+        assert_eq!(super::hosts_diff(&new, &new), "");
+    }
+
+    #[test]
+    pub fn shell_quote_escapes_everything() {
+        assert_eq!(super::shell_quote("1.1.1.1\tweb"), "'1.1.1.1\tweb'");
+        assert_eq!(super::shell_quote(r#"say "hi""#), r#"'say "hi"'"#);
+        assert_eq!(super::shell_quote("it's"), r#"'it'\''s'"#);
+        assert_eq!(super::shell_quote(r"back\slash"), r"'back\slash'");
+        assert_eq!(super::shell_quote("$HOME `id`"), "'$HOME `id`'");
+        assert_eq!(super::shell_quote("a\nb\n"), "'a\nb\n'");
+    }
+
+    #[test]
+    pub fn upload_host_file_host_file() {
+        let host_file = format!(
+            "127.0.0.1	localhost
+
+# The following lines are desirable for IPv6 capable hosts
+::1     ip6-localhost ip6-loopback
+fe00::0 ip6-localnet
+ff00::0 ip6-mcastprefix
+ff02::1 ip6-allnodes
+ff02::2 ip6-allrouters
+::1 traefik.localhost
+::1 custom_app.localhost
+### open {} network target
+### close {} network target
+1.1.1.1 aze
+", PACKAGE, PACKAGE);
+        let lines = vec![
+            "web".into(),
+            "api".into(),
+        ];
+        let s = super::update_host_file(host_file, &lines, &GUARD, "1.1.1.1", None);
+        assert_eq!(s,
+            format!(
+"127.0.0.1	localhost
 
 # The following lines are desirable for IPv6 capable hosts
 ::1     ip6-localhost ip6-loopback
@@ -352,6 +2917,1827 @@ ff02::2 ip6-allrouters
 ### close {} network target
 ", PACKAGE, PACKAGE)
         );
+
+        let lines = super::suffixed(&["web".into(), "api.localhost".into(), String::new()], Some(".localhost"));
+        let s = super::update_host_file(s, &lines, &GUARD, "1.1.1.1", None);
+        assert!(s.ends_with(&format!(
+"1.1.1.1 aze
+### open {} network target
+1.1.1.1\tweb.localhost
+1.1.1.1\tapi.localhost
+### close {} network target
+", PACKAGE, PACKAGE)));
+    }
+
+    #[test]
+    pub fn poll_labels_cover_the_flag_and_the_targets() {
+        let stack = stack();
+
+        assert_eq!(stack.config.poll_filters(), vec![
+            PollFilter::Label("flag".into()),
+            PollFilter::Label("com.docker.compose.service=web".into()),
+        ]);
+    }
+
+    #[test]
+    pub fn literal_targets_match_exactly() {
+        let targets = stack().config.targets().unwrap();
+
+        assert!(targets.matches("web"));
+        assert!(!targets.matches("web-1"));
+        assert!(!targets.matches("webapp"));
+    }
+
+    #[test]
+    pub fn regex_targets_match_every_replica() {
+        let mut config = stack().config;
+        config.target = vec![r"^web-\d+$".into()];
+        config.target_is_regex = true;
+        let targets = config.targets().unwrap();
+
+        assert!(targets.matches("web-1"));
+        assert!(targets.matches("web-12"));
+        assert!(!targets.matches("web"));
+        assert_eq!(config.poll_filters(), vec![PollFilter::Label("flag".into()), PollFilter::Label("com.docker.compose.service".into())]);
+    }
+
+    #[test]
+    pub fn invalid_regex_target_is_rejected() {
+        let mut config = stack().config;
+        config.target = vec!["web-(".into()];
+        config.target_is_regex = true;
+
+        let error = config.validate().unwrap_err();
+
+        assert!(matches!(error, BusinessError::Config(ConfigError::Pattern(_))));
+    }
+
+    #[tokio::test]
+    pub async fn each_regex_replica_gets_a_target_event() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true)],
+            vec![
+                raw("api0000000000000000", "api", "1.1.1.2", true),
+                raw("web1000000000000000", "web-1", "1.1.1.1", false),
+                raw("web2000000000000000", "web-2", "1.1.1.3", false),
+            ],
+        ]);
+        let mut config = stack().config;
+        config.target = vec![r"^web-\d+$".into()];
+        config.target_is_regex = true;
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        let mut updates = docker.updated();
+        updates.sort();
+        assert_eq!(updates, vec![
+            ("api0000000000000000".to_string(), "1.1.1.1".to_string()),
+            ("api0000000000000000".to_string(), "1.1.1.3".to_string()),
+        ]);
+        assert_eq!(stack.target_ip.len(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn mesh_routes_flagged_containers_to_each_other() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("db00000000000000000", "db", "1.1.1.3", true),
+            raw("cache00000000000000", "cache", "1.1.1.4", true),
+        ]]);
+        let mut config = stack().config;
+        config.mesh = true;
+        let mut stack = CurrentStack::new(config);
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let hosts = docker.hosts.lock().unwrap();
+        assert_eq!(hosts["api0000000000000000"], "### open local_stack_focus network mesh\n1.1.1.4\tcache\n1.1.1.3\tdb\n### close local_stack_focus network mesh\n");
+        assert_eq!(hosts["db00000000000000000"], "### open local_stack_focus network mesh\n1.1.1.2\tapi\n1.1.1.4\tcache\n### close local_stack_focus network mesh\n");
+        assert_eq!(hosts["cache00000000000000"], "### open local_stack_focus network mesh\n1.1.1.2\tapi\n1.1.1.3\tdb\n### close local_stack_focus network mesh\n");
+    }
+
+    #[tokio::test]
+    pub async fn mesh_replicas_sharing_a_service_are_reported() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("db10000000000000000", "db", "1.1.1.3", true),
+            raw("db20000000000000000", "db", "1.1.1.5", true),
+        ]]);
+        let mut config = stack().config;
+        config.mesh = true;
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        // the first replica is kept rather than whichever comes last
+        assert_eq!(docker.hosts.lock().unwrap()["api0000000000000000"], "### open local_stack_focus network mesh\n1.1.1.3\tdb\n### close local_stack_focus network mesh\n");
+        assert!(String::from_utf8(output).unwrap().contains(
+            "WARN hostname db points at different ips in containers db10000000000000, db20000000000000\n"
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn a_labeled_container_is_registered_under_its_alias() {
+        let mut api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        api.labels.insert(super::ALIAS_LABEL.into(), "api.local".into());
+        let mut docker = MockDocker::new(vec![vec![api, raw("db00000000000000000", "db", "1.1.1.3", true)]]);
+        let mut config = stack().config;
+        config.mesh = true;
+        let mut stack = CurrentStack::new(config);
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let hosts = docker.hosts.lock().unwrap();
+        assert!(hosts["db00000000000000000"].contains("1.1.1.2\tapi.local\n"), "{}", hosts["db00000000000000000"]);
+        assert!(hosts["api0000000000000000"].contains("1.1.1.3\tdb\n"), "{}", hosts["api0000000000000000"]);
+    }
+
+    #[tokio::test]
+    pub async fn without_require_flag_unflagged_containers_are_routed() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", false),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        let mut stack = stack();
+        stack.config.require_flag = Some(false);
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        assert_eq!(stack.config.poll_filters(), vec![PollFilter::Network("network".into()), PollFilter::Label("com.docker.compose.service=web".into())]);
+        // the target is not routed to itself
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[test]
+    pub fn resolved_config_prints_its_defaults() {
+        let printed = toml::to_string(&stack().config.resolved()).unwrap();
+
+        assert!(printed.contains("target = [\"web\"]\n"));
+        assert!(printed.contains("poll_interval_ms = 1000\n"));
+        assert!(printed.contains("guard_prefix = \"###\"\n"));
+        assert!(printed.contains("log_format = \"text\"\n"));
+        assert!(!printed.contains("metrics_addr"));
+    }
+
+    #[test]
+    pub fn relative_hosts_path_is_rejected() {
+        let mut config = stack().config;
+        assert_eq!(config.hosts_path(), "/etc/hosts");
+
+        config.hosts_path = Some("etc/hosts".into());
+        let error = config.validate().unwrap_err();
+
+        assert!(matches!(error, BusinessError::Config(ConfigError::Relative("hosts_path"))));
+    }
+
+    #[test]
+    pub fn config_defaults() {
+        let config = toml::from_str::<Config>(r#"
+network = "network"
+label_key = "label"
+target = "target"
+dependencies = []
+"#).unwrap();
+        assert_eq!(config.poll_interval(), Duration::from_secs(1));
+        assert_eq!(config.target, vec!["target".to_string()]);
+        assert_eq!(config.network, vec!["network".to_string()]);
+
+        let config = toml::from_str::<Config>(r#"
+network = ["frontend_net", "backend_net"]
+label_key = "label"
+target = ["web", "admin"]
+dependencies = []
+poll_interval_ms = 0
+"#).unwrap();
+        assert_eq!(config.poll_interval(), Duration::from_millis(0));
+        assert_eq!(config.target, vec!["web".to_string(), "admin".to_string()]);
+        assert_eq!(config.network, vec!["frontend_net".to_string(), "backend_net".to_string()]);
+    }
+
+    #[test]
+    pub fn errors_keep_their_kind() {
+        let io: BusinessError = std::io::Error::other("disk full").into();
+        let toml: BusinessError = toml::from_str::<Config>("network =").unwrap_err().into();
+        let docker: BusinessError = DockerError::Connection("refused".into()).into();
+
+        assert!(matches!(io, BusinessError::Io(_)));
+        assert_eq!(io.to_string(), "disk full");
+        assert!(matches!(toml, BusinessError::Toml(_)));
+        assert!(super::is_connection_error(&docker));
+        assert_eq!(docker.to_string(), "lost connection to docker daemon: refused");
+    }
+
+    #[test]
+    pub fn validate_names_the_empty_field() {
+        let config = toml::from_str::<Config>(r#"
+network = "network"
+label_key = ""
+target = "target"
+dependencies = ["web"]
+"#).unwrap();
+        assert_eq!(config.validate().unwrap_err().to_string(), "config field label_key must not be empty");
+
+        let config = toml::from_str::<Config>(r#"
+network = []
+label_key = "label"
+target = "target"
+dependencies = ["web"]
+"#).unwrap();
+        assert_eq!(config.validate().unwrap_err().to_string(), "config field network must not be empty");
+    }
+
+    #[test]
+    pub fn validate_accepts_dotted_and_hyphenated_dependencies() {
+        let config = toml::from_str::<Config>(r#"
+network = "network"
+label_key = "label"
+target = "target"
+dependencies = ["web", "api.local", "my-db", "cache_1"]
+"#).unwrap();
+
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    pub fn validate_lists_invalid_dependencies() {
+        let config = toml::from_str::<Config>(r#"
+network = "network"
+label_key = "label"
+target = "target"
+dependencies = ["web", "my api", "db\tlocal", "ok"]
+"#).unwrap();
+
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "config field dependencies holds invalid hostnames: my api, db\tlocal"
+        );
+    }
+
+    #[tokio::test]
+    pub async fn with_backoff_retries_transient_errors() {
+        let config = toml::from_str::<Config>(r#"
+network = "network"
+label_key = "label"
+target = "target"
+dependencies = []
+retry_base_delay_ms = 0
+timestamps = false
+"#).unwrap();
+        let mut output = Vec::new();
+        let mut calls = 0;
+
+        let result = with_backoff(&mut output, &config, || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                if attempt < 3 {
+                    Err(DockerError::Transient("500".into()).into())
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }).await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "WARN docker daemon failed to answer: 500, retrying in 0ms (attempt 1/3)
+WARN docker daemon failed to answer: 500, retrying in 0ms (attempt 2/3)
+"
+        );
+    }
+
+    #[test]
+    pub fn upload_host_file_dual_stack() {
+        let lines = vec![
+            "web".into(),
+            "api".into(),
+        ];
+        let s = super::update_host_file("127.0.0.1\tlocalhost\n".into(), &lines, &GUARD, "1.2.3.4", Some("fe80::1"));
+        assert_eq!(s,
+            format!(
+"127.0.0.1\tlocalhost
+### open {} network target
+1.2.3.4\tweb
+fe80::1\tweb
+1.2.3.4\tapi
+fe80::1\tapi
+### close {} network target
+", PACKAGE, PACKAGE)
+        );
+    }
+
+    #[test]
+    pub fn upload_host_file_deduplicates_lines() {
+        let lines = vec![
+            "web".into(),
+            "api".into(),
+            "web".into(),
+        ];
+        let s = super::update_host_file(String::new(), &lines, &GUARD, "1.1.1.1", None);
+        assert_eq!(s,
+            format!(
+"### open {} network target
+1.1.1.1\tweb
+1.1.1.1\tapi
+### close {} network target
+", PACKAGE, PACKAGE)
+        );
+    }
+
+    #[test]
+    pub fn clear_host_file_removes_the_guard_block() {
+        let lines = vec!["web".into()];
+        let host_file = super::update_host_file("127.0.0.1\tlocalhost\n".into(), &lines, &GUARD, "1.1.1.1", None);
+
+        assert_eq!(super::clear_host_file(host_file, &GUARD), "127.0.0.1\tlocalhost\n");
+    }
+
+    #[test]
+    pub fn custom_guard_prefix_round_trip() {
+        let guard = Guard { prefix: "# lsf:", ..GUARD };
+        let lines = vec!["web".into()];
+        let host_file = super::update_host_file("127.0.0.1\tlocalhost\n".into(), &lines, &guard, "1.1.1.1", None);
+
+        assert_eq!(host_file, format!(
+"127.0.0.1\tlocalhost
+# lsf: open {} network target
+1.1.1.1\tweb
+# lsf: close {} network target
+", PACKAGE, PACKAGE));
+        assert_eq!(super::update_host_file(host_file.clone(), &lines, &guard, "1.1.1.1", None), host_file);
+        assert_eq!(super::clear_host_file(host_file, &guard), "127.0.0.1\tlocalhost\n");
+    }
+
+    #[tokio::test]
+    pub async fn dry_run_logs_the_guard_block_without_writing() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true)],
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.dry_run = true;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert!(docker.updates.lock().unwrap().is_empty());
+        assert!(String::from_utf8(output).unwrap().contains(&format!(
+            "INFO dry run: would write to container api0000000000000:
+### open {} network web
+1.1.1.1\tapi
+### close {} network web
+", PACKAGE, PACKAGE)));
+    }
+
+    #[test]
+    pub fn string_vec_parses_comma_separated_hostnames() {
+        assert_eq!(StringVec::parse(" app1.local, app2.local,").unwrap().as_slice(), ["app1.local", "app2.local"]);
+        assert!(StringVec::parse("true").is_none());
+        assert!(StringVec::parse(" , ").is_none());
+    }
+
+    #[tokio::test]
+    pub async fn flag_label_aliases_override_dependencies() {
+        let mut api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        api.labels.insert("flag".into(), "app1.local,app2.local".into());
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api, raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.dry_run = true;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert!(String::from_utf8(output).unwrap().contains(&format!(
+            "INFO dry run: would write to container api0000000000000:
+### open {} network web
+1.1.1.1\tapp1.local
+1.1.1.1\tapp2.local
+### close {} network web
+", PACKAGE, PACKAGE)));
+    }
+
+    #[test]
+    pub fn string_vec_reads_compose_depends_on() {
+        assert_eq!(StringVec::depends_on("db:service_started:false,redis:service_healthy:true").as_slice(), ["db", "redis"]);
+        assert_eq!(StringVec::depends_on("db").as_slice(), ["db"]);
+        assert!(StringVec::depends_on("").as_slice().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn dependencies_from_labels_replace_the_static_list() {
+        let mut api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        api.labels.insert("com.docker.compose.depends_on".into(), "db:service_started:false,cache:service_healthy:true".into());
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api, raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.dry_run = true;
+        stack.config.dependencies_from_labels = true;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert!(String::from_utf8(output).unwrap().contains(&format!(
+            "INFO dry run: would write to container api0000000000000:
+### open {} network web
+1.1.1.1\tdb
+1.1.1.1\tcache
+### close {} network web
+", PACKAGE, PACKAGE)));
+    }
+
+    #[tokio::test]
+    pub async fn hostname_routed_to_two_ips_is_reported() {
+        let mut api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        api.labels.insert("flag".into(), "shared.local".into());
+        let mut admin = raw("adm0000000000000000", "admin", "1.1.1.4", true);
+        admin.labels.insert("flag".into(), "shared.local".into());
+        let mut docker = MockDocker::new(vec![vec![
+            api,
+            admin,
+            raw("web1000000000000000", "web-1", "1.1.1.1", false),
+            raw("web2000000000000000", "web-2", "1.1.1.3", false),
+        ]]);
+        let mut config = stack().config;
+        config.target = vec![r"^web-\d+$".into()];
+        config.target_is_regex = true;
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(stack.hostname_conflicts(), vec![(
+            "shared.local".to_string(),
+            vec!["adm0000000000000".to_string(), "api0000000000000".to_string()],
+        )]);
+        assert!(String::from_utf8(output).unwrap().contains(
+            "WARN hostname shared.local points at different ips in containers adm0000000000000, api0000000000000\n"
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn flagged_containers_sharing_an_alias_are_reported() {
+        let aliased = |id: &str, service: &str, ip: &str| {
+            let mut raw = raw(id, service, ip, true);
+            raw.labels.insert(super::ALIAS_LABEL.into(), "backend".into());
+            raw
+        };
+        let mut docker = MockDocker::new(vec![vec![
+            aliased("api0000000000000000", "api", "1.1.1.2"),
+            aliased("adm0000000000000000", "admin", "1.1.1.4"),
+            raw("db00000000000000000", "db", "1.1.1.3", true),
+        ]]);
+        let mut config = stack().config;
+        config.mesh = true;
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(stack.hostname_conflicts(), vec![(
+            "backend".to_string(),
+            vec!["adm0000000000000".to_string(), "api0000000000000".to_string()],
+        )]);
+        assert!(String::from_utf8(output).unwrap().contains(
+            "WARN hostname backend points at different ips in containers adm0000000000000, api0000000000000\n"
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn one_target_per_network_is_no_hostname_conflict() {
+        let on_other = |id: &str, service: &str, ip: &str, flagged: bool| {
+            let mut raw = raw(id, service, ip, flagged);
+            raw.networks = HashMap::from([("other".to_string(), ip.to_string())]);
+            raw
+        };
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+            on_other("adm0000000000000000", "admin", "1.1.9.4", true),
+            on_other("prx0000000000000000", "proxy", "1.1.9.1", false),
+        ]]);
+        let mut config = stack().config;
+        config.network = vec!["network".into(), "other".into()];
+        config.target = vec!["web".into(), "proxy".into()];
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(stack.target_ip.len(), 2);
+        assert!(stack.hostname_conflicts().is_empty());
+        assert!(!String::from_utf8(output).unwrap().contains("points at different ips"));
+    }
+
+    #[test]
+    pub fn log_writers_are_told_the_level_of_each_line() {
+        #[derive(Default)]
+        struct Leveled {
+            levels: Vec<super::Level>,
+            written: Vec<u8>,
+        }
+
+        impl std::io::Write for Leveled {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.written.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl super::LogWrite for Leveled {
+            fn level(&mut self, level: super::Level) {
+                self.levels.push(level);
+            }
+        }
+
+        let mut config = stack().config;
+        config.log_format = LogFormat::Json;
+        let mut writer = Leveled::default();
+
+        super::json(&mut writer, &config, super::Level::Error, serde_json::json!({"event": "error"})).unwrap();
+        config.log_format = LogFormat::Text;
+        super::text(&mut writer, &config, super::Level::Warn, format_args!("careful")).unwrap();
+
+        assert_eq!(writer.levels, vec![super::Level::Error, super::Level::Warn]);
+    }
+
+    #[tokio::test]
+    pub async fn json_log_format_writes_one_object_per_event() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true)],
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.log_format = LogFormat::Json;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(), r#"{"container":"api0000000000000","event":"new","level":"INFO","targets":[]}
+{"applied_to":1,"container":"web0000000000000","event":"target","ip":"1.1.1.1","ipv6":null,"level":"NOTICE","service":"web"}
+"#);
+    }
+
+    #[tokio::test]
+    pub async fn json_summary_counts_gone_containers() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.log_format = LogFormat::Json;
+        stack.config.summary = true;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.ends_with("{\"errors\":0,\"event\":\"summary\",\"flagged\":0,\"gone\":1,\"ignored\":0,\"level\":\"INFO\",\"targets\":0,\"updates\":0}\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn target_ip_change_is_applied_again() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api.clone(), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![api.clone(), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![api, raw("web0000000000000000", "web", "1.1.1.3", false)],
+        ]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        for _ in 0..4 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(docker.updated(), vec![
+            ("api0000000000000000".to_string(), "1.1.1.1".to_string()),
+            ("api0000000000000000".to_string(), "1.1.1.3".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    pub async fn metrics_follow_the_processed_events() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true)],
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        assert!(stack.metrics.render().contains("lsf_target_known 0\n"));
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        let rendered = stack.metrics.render();
+        assert!(rendered.contains("lsf_updates_total 1\n"));
+        assert!(rendered.contains("lsf_flagged_containers 1\n"));
+        assert!(rendered.contains("lsf_target_known 1\n"));
+    }
+
+    #[tokio::test]
+    pub async fn oneshot_applies_once_and_returns() {
+        let docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut config = stack().config;
+        config.oneshot = true;
+
+        event_loop(docker, Vec::new(), config).await.unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn oneshot_without_target_fails() {
+        let docker = MockDocker::new(vec![vec![raw("api0000000000000000", "api", "1.1.1.2", true)]]);
+        let mut config = stack().config;
+        config.oneshot = true;
+
+        let error = event_loop(docker, Vec::new(), config).await.unwrap_err();
+
+        assert!(matches!(error, BusinessError::Stack(StackError::NoTarget(_))));
+    }
+
+    #[tokio::test]
+    pub async fn containers_are_routed_whatever_the_start_order() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut worker = raw("wrk0000000000000000", "worker", "1.1.1.5", true);
+        worker.labels.insert("flag".into(), "queue.local".into());
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api.clone(), web.clone()],
+            vec![api.clone(), web.clone(), worker.clone()],
+            vec![api, web, worker],
+        ]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        for _ in 0..4 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(*docker.updates.lock().unwrap(), vec![
+            Update {
+                id: "api0000000000000000".into(),
+                dependencies: vec!["api".into()],
+                network: "network".into(),
+                target: "web".into(),
+                ip: "1.1.1.1".into(),
+            },
+            Update {
+                id: "wrk0000000000000000".into(),
+                dependencies: vec!["queue.local".into()],
+                network: "network".into(),
+                target: "web".into(),
+                ip: "1.1.1.1".into(),
+            },
+        ]);
+    }
+
+    #[tokio::test]
+    pub async fn unflagged_and_outside_containers_are_never_updated() {
+        let mut outside = raw("out0000000000000000", "out", "1.1.1.6", true);
+        outside.networks = HashMap::from([("other".to_string(), "1.1.1.6".to_string())]);
+        let mut docker = MockDocker::new(vec![
+            vec![raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![raw("web0000000000000000", "web", "1.1.1.1", false), raw("idl0000000000000000", "idle", "1.1.1.7", false), outside],
+        ]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert!(docker.updates.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn flagged_containers_seen_with_the_target_are_routed_once() {
+        let mut poll: Vec<_> = (0..8).map(|i| raw(&format!("api{}000000000000000", i), &format!("api{}", i), &format!("1.1.2.{}", i), true)).collect();
+        poll.push(raw("web0000000000000000", "web", "1.1.1.1", false));
+        let mut docker = MockDocker::new(vec![poll]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        let mut updates = docker.updated();
+        updates.sort();
+        assert_eq!(updates, (0..8).map(|i| (format!("api{}000000000000000", i), "1.1.1.1".to_string())).collect::<Vec<_>>());
+        assert!(!String::from_utf8(output).unwrap().contains("no target known yet"));
+    }
+
+    #[tokio::test]
+    pub async fn flagged_container_is_routed_once_the_target_appears() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api.clone(), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![api, raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        assert!(docker.updated().is_empty());
+        assert!(String::from_utf8(output.clone()).unwrap().contains("because no target known yet"));
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn container_accessors_expose_the_located_state() {
+        let mut docker = MockDocker::new(vec![vec![raw("api0000000000000000", "api", "1.1.1.2", true)]]);
+        let mut stack = stack();
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let container = &stack.map.as_ref().unwrap()["api0000000000000000"];
+
+        assert_eq!(container.ip(), Some("1.1.1.2"));
+        assert_eq!(container.service(), Some("api"));
+        assert_eq!(container.flag(), Some("true"));
+    }
+
+    #[tokio::test]
+    pub async fn flagged_container_leaving_the_network_is_reported() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let mut gone = api.clone();
+        gone.networks = HashMap::from([("other".to_string(), "1.1.9.2".to_string())]);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut docker = MockDocker::new(vec![
+            vec![api, web.clone()],
+            vec![gone.clone(), web.clone()],
+            vec![gone, web],
+        ]);
+        let mut stack = stack();
+        stack.config.cleanup_on_lost_network = true;
+        let mut output = Vec::new();
+
+        for _ in 0..3 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("WARN event container lost network: container api0000000000000").count(), 1);
+        assert_eq!(*docker.clears.lock().unwrap(), vec![("api0000000000000000".to_string(), "network".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn ignored_container_is_logged_once() {
+        let idle = raw("idl0000000000000000", "idle", "1.1.1.7", false);
+        let mut docker = MockDocker::new(vec![vec![idle.clone()], vec![idle.clone()], vec![idle]]);
+        let mut stack = stack();
+        let mut output = Vec::new();
+
+        for _ in 0..3 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(), "INFO event container ignored (label): container idl0000000000000 unflagged service idle named idle in network network at ip 1.1.1.7\n");
+    }
+
+    #[tokio::test]
+    pub async fn log_ignored_silences_ignored_containers() {
+        let mut docker = MockDocker::new(vec![vec![raw("idl0000000000000000", "idle", "1.1.1.7", false)]]);
+        let mut stack = stack();
+        stack.config.log_ignored = Some(false);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn summary_line_ends_each_poll() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("idl0000000000000000", "idle", "1.1.1.7", false)],
+            vec![
+                raw("api0000000000000000", "api", "1.1.1.2", true),
+                raw("idl0000000000000000", "idle", "1.1.1.7", false),
+                raw("web0000000000000000", "web", "1.1.1.1", false),
+            ],
+        ]);
+        let mut stack = stack();
+        stack.config.summary = true;
+        stack.config.log_ignored = Some(false);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let summaries: Vec<_> = output.lines().filter(|line| line.contains("poll complete")).collect();
+        assert_eq!(summaries, vec![
+            "INFO poll complete: 1 flagged, 0 target, 0 gone, 1 ignored, 0 updates applied, 0 errors",
+            "INFO poll complete: 0 flagged, 1 target, 0 gone, 0 ignored, 1 updates applied, 0 errors",
+        ]);
+    }
+
+    #[tokio::test]
+    pub async fn only_the_configured_project_is_watched() {
+        let in_project = |id: &str, service: &str, ip: &str, flagged: bool, project: &str| {
+            let mut raw = raw(id, service, ip, flagged);
+            raw.labels.insert("com.docker.compose.project".into(), project.into());
+            raw
+        };
+        let mut docker = MockDocker::new(vec![vec![
+            in_project("api0000000000000000", "api", "1.1.1.2", true, "shop"),
+            in_project("web0000000000000000", "web", "1.1.1.1", false, "shop"),
+            in_project("apb0000000000000000", "api", "1.1.1.4", true, "blog"),
+            in_project("wbb0000000000000000", "web", "1.1.1.3", false, "blog"),
+        ]]);
+        let mut stack = stack();
+        stack.config.project = Some("shop".into());
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("apb0000000000000"));
+        assert!(!output.contains("wbb0000000000000"));
+    }
+
+    #[test]
+    pub fn label_presence_flags_without_label_value() {
+        let config = stack().config;
+
+        assert!(config.flags("true"));
+        assert!(config.flags("disabled"));
+    }
+
+    #[tokio::test]
+    pub async fn label_value_must_match_to_flag() {
+        let mut enabled = raw("api0000000000000000", "api", "1.1.1.2", true);
+        enabled.labels.insert("flag".into(), "enabled".into());
+        let mut disabled = raw("adm0000000000000000", "admin", "1.1.1.4", true);
+        disabled.labels.insert("flag".into(), "disabled".into());
+        let mut docker = MockDocker::new(vec![vec![enabled, disabled, raw("web0000000000000000", "web", "1.1.1.1", false)]]);
+        let mut stack = stack();
+        stack.config.label_value = Some("enabled".into());
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(*docker.updates.lock().unwrap(), vec![Update {
+            id: "api0000000000000000".into(),
+            dependencies: vec!["api".into()],
+            network: "network".into(),
+            target: "web".into(),
+            ip: "1.1.1.1".into(),
+        }]);
+        assert!(String::from_utf8(output).unwrap().contains("event container ignored (label): container adm0000000000000"));
+        assert_eq!(stack.config.poll_filters()[0], PollFilter::Label("flag=enabled".into()));
+    }
+
+    #[tokio::test]
+    pub async fn quiet_only_logs_target_changes() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("idl0000000000000000", "idle", "1.1.1.7", false)],
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.verbosity = Verbosity::Quiet;
+        let mut output = Vec::new();
+
+        for _ in 0..2 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(String::from_utf8(output).unwrap(), "WARN could not update /etc/hosts for container api0000000000000 because no target known yet, it will be once one is found
+NOTICE event found target: container web0000000000000 unflagged service web named web in network network at ip 1.1.1.1 applying it to known 1 containers
+NOTICE recording ip for target web: 1.1.1.1
+");
+    }
+
+    #[tokio::test]
+    pub async fn verbose_logs_every_poll() {
+        let mut docker = MockDocker::new(vec![vec![]]);
+        let mut stack = stack();
+        stack.config.verbosity = Verbosity::Verbose;
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "DEBUG polled 0 containers, 0 events
+DEBUG poll complete: 0 flagged, 0 target, 0 gone, 0 ignored, 0 updates applied, 0 errors
+");
+    }
+
+    #[test]
+    pub fn verbosity_parses_from_the_environment() {
+        assert_eq!(Verbosity::parse("Quiet"), Some(Verbosity::Quiet));
+        assert_eq!(Verbosity::parse("verbose"), Some(Verbosity::Verbose));
+        assert_eq!(Verbosity::parse("loud"), None);
+    }
+
+    #[tokio::test]
+    pub async fn identical_hosts_are_not_written_again() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone(), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![api.clone()],
+            vec![api, raw("web1000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut stack = stack();
+        stack.config.summary = true;
+        let mut output = Vec::new();
+
+        for _ in 0..3 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(docker.updates.lock().unwrap().len(), 2);
+        assert_eq!(*docker.writes.lock().unwrap(), 1);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("INFO hosts already up to date for container api0000000000000\n"));
+        assert!(output.ends_with("INFO poll complete: 0 flagged, 1 target, 0 gone, 0 ignored, 0 updates applied, 0 errors\n"));
+    }
+
+    #[test]
+    pub fn annotation_follows_the_open_guard_and_is_trimmed_with_the_block() {
+        let guard = Guard { annotate: true, ..GUARD };
+        let file = super::update_host_file("127.0.0.1\tlocalhost\n".into(), &["api".into()], &guard, "1.1.1.1", None);
+
+        let mut lines = file.lines().skip(2);
+        assert!(lines.next().unwrap().starts_with("# managed by local_stack_focus, target=target, updated="), "{}", file);
+        assert_eq!(lines.next(), Some("1.1.1.1\tapi"));
+        assert_eq!(super::clear_host_file(file, &guard), "127.0.0.1\tlocalhost\n");
+    }
+
+    #[test]
+    pub fn extra_hosts_are_written_and_trimmed_with_the_block() {
+        let extra_hosts = std::collections::BTreeMap::from([
+            ("host.docker.internal".to_string(), "172.17.0.1".to_string()),
+            ("registry.local".to_string(), "10.0.0.5".to_string()),
+        ]);
+        let guard = Guard { extra_hosts: Some(&extra_hosts), ..GUARD };
+        let file = super::update_host_file("127.0.0.1\tlocalhost\n".into(), &["api".into()], &guard, "1.1.1.1", None);
+
+        assert_eq!(file, "127.0.0.1\tlocalhost\n### open local_stack_focus network target\n1.1.1.1\tapi\n172.17.0.1\thost.docker.internal\n10.0.0.5\tregistry.local\n### close local_stack_focus network target\n");
+        assert_eq!(super::clear_host_file(file, &guard), "127.0.0.1\tlocalhost\n");
+    }
+
+    #[test]
+    pub fn hosts_update_ignores_the_annotation_date() {
+        let old = "### open\n# managed by local_stack_focus, target=web, updated=2024-01-01T00:00:00Z\n1.1.1.1\tapi\n";
+        let new = "### open\n# managed by local_stack_focus, target=web, updated=2024-06-01T00:00:00Z\n1.1.1.1\tapi\n";
+
+        assert_eq!(super::hosts_update(old, new), HostsUpdate::Unchanged);
+    }
+
+    #[test]
+    pub fn hosts_update_compares_the_content() {
+        assert_eq!(super::hosts_update("1.1.1.1\tapi\n", "1.1.1.1\tapi\n"), HostsUpdate::Unchanged);
+        assert_eq!(super::hosts_update("", "1.1.1.1\tapi\n"), HostsUpdate::Changed("+1.1.1.1\tapi\n".into()));
+    }
+
+    #[tokio::test]
+    pub async fn missing_ip_is_inspected_when_enabled() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let mut listed = api.clone();
+        listed.networks = HashMap::from([("network".to_string(), String::new())]);
+        let poll = vec![listed, raw("web0000000000000000", "web", "1.1.1.1", false)];
+
+        let mut docker = MockDocker::new(vec![poll.clone()]);
+        let mut without = stack();
+        let mut output = Vec::new();
+        without.loop_once(&mut docker, &mut output).await.unwrap();
+        assert!(docker.updated().is_empty());
+        assert!(String::from_utf8(output).unwrap().contains("event container ignored (network): container api0000000000000"));
+
+        let mut docker = MockDocker::new(vec![poll]);
+        docker.inspected.lock().unwrap().insert(api.id.clone(), api);
+        let mut with = stack();
+        with.config.inspect_missing_ip = true;
+        with.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn containers_on_another_network_than_the_target_are_skipped() {
+        let on_other = |id: &str, service: &str, ip: &str| {
+            let mut raw = raw(id, service, ip, true);
+            raw.networks = HashMap::from([("other".to_string(), ip.to_string())]);
+            raw
+        };
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let admin = on_other("adm0000000000000000", "admin", "1.1.9.4");
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone(), admin.clone()],
+            vec![api.clone(), admin.clone(), web.clone()],
+            vec![api, admin, web, on_other("wrk0000000000000000", "worker", "1.1.9.5")],
+        ]);
+        let mut stack = stack();
+        stack.config.network = vec!["network".into(), "other".into()];
+        let mut output = Vec::new();
+
+        for _ in 0..3 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn target_and_new_paths_write_the_same_guard_block() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone()],
+            vec![api.clone(), web.clone()],
+            vec![web.clone()],
+            vec![api.clone(), web],
+        ]);
+        let mut stack = stack();
+
+        for _ in 0..4 {
+            stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        }
+
+        assert_eq!(docker.updated().len(), 2);
+        let hosts = docker.hosts.lock().unwrap();
+        let file = &hosts[&api.id];
+        assert_eq!(file.matches(" open ").count(), 1, "{}", file);
+        assert_eq!(file.matches(" close ").count(), 1, "{}", file);
+    }
+
+    #[tokio::test]
+    pub async fn targets_are_restored_from_the_target_file() {
+        let path = std::env::temp_dir().join(format!("lsf-targets-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut config = stack().config;
+        config.target_file = Some(path.to_string_lossy().into_owned());
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+
+        let mut first = CurrentStack::new(config.clone());
+        first.loop_once(&mut MockDocker::new(vec![vec![web.clone()]]), &mut Vec::new()).await.unwrap();
+
+        let restored = CurrentStack::new(config.clone());
+        assert_eq!(restored.target_ip, first.target_ip);
+        assert!(restored.restoring);
+
+        // a target still running is kept, one gone since is forgotten
+        let mut kept = CurrentStack::new(config.clone());
+        kept.loop_once(&mut MockDocker::new(vec![vec![web]]), &mut Vec::new()).await.unwrap();
+        assert_eq!(kept.target_ip.len(), 1);
+
+        let mut stale = CurrentStack::new(config);
+        stale.loop_once(&mut MockDocker::new(vec![vec![]]), &mut Vec::new()).await.unwrap();
+        assert!(stale.target_ip.is_empty());
+        assert_eq!(super::load_targets(&path.to_string_lossy()), Ok(HashMap::new()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn config_warnings_are_logged_on_the_first_poll() {
+        let mut config = stack().config;
+        config.dependencies = StringVec::default();
+        let mut stack = CurrentStack::new(config);
+        let mut docker = MockDocker::new(vec![vec![], vec![]]);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("WARN config field dependencies is empty, no host will be routed").count(), 1, "{}", output);
+    }
+
+    #[test]
+    pub fn syslog_without_the_feature_is_warned_about() {
+        let mut config = stack().config;
+        config.syslog = true;
+
+        let warned = config.warnings().iter().any(|warning| warning.starts_with("syslog is set but"));
+
+        assert_eq!(warned, !cfg!(feature = "syslog"));
+    }
+
+    #[tokio::test]
+    pub async fn health_file_is_touched_after_a_successful_loop() {
+        let path = std::env::temp_dir().join(format!("lsf-health-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut config = stack().config;
+        config.oneshot = true;
+        config.health_file = Some(path.to_string_lossy().into_owned());
+
+        event_loop(docker, Vec::new(), config).await.unwrap();
+
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn health_file_failure_only_warns() {
+        let docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        let mut config = stack().config;
+        config.oneshot = true;
+        config.health_file = Some("/nonexistent/lsf-alive".into());
+        let mut output = Vec::new();
+
+        event_loop(docker, &mut output, config).await.unwrap();
+
+        assert!(String::from_utf8(output).unwrap().contains("WARN could not touch health file /nonexistent/lsf-alive"));
+    }
+
+    #[tokio::test]
+    pub async fn service_is_read_from_a_custom_label_key() {
+        let relabel = |mut raw: RawContainer| {
+            let service = raw.labels.remove("com.docker.compose.service").unwrap();
+            raw.labels.insert("app.service".into(), service);
+            raw
+        };
+        let mut docker = MockDocker::new(vec![vec![
+            relabel(raw("api0000000000000000", "api", "1.1.1.2", true)),
+            relabel(raw("web0000000000000000", "web", "1.1.1.1", false)),
+        ]]);
+        let mut stack = stack();
+        stack.config.service_label_key = Some("app.service".into());
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        assert_eq!(stack.config.poll_filters(), vec![PollFilter::Label("flag".into()), PollFilter::Label("app.service=web".into())]);
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn run_until_stops_after_the_given_polls() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let docker = MockDocker::new(vec![vec![api.clone()], vec![api.clone(), web.clone()], vec![api, web]]);
+        let mut config = stack().config;
+        config.poll_interval_ms = Some(1);
+        let mut output = Vec::new();
+        let mut polls = 0;
+
+        run_until(docker, &mut output, config, NoReload, || {
+            polls += 1;
+            polls < 3
+        }).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(polls, 3);
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some(concat!("INFO ", env!("CARGO_PKG_NAME"), " version ", env!("CARGO_PKG_VERSION"))));
+        assert_eq!(lines.next(), Some("INFO Looking for containers in network network with label flag to be routed via service «web» as [api]"));
+        assert!(output.contains("NOTICE event found target: container web0000000000000"), "{}", output);
+        assert_eq!(output.matches("event found target").count(), 1, "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn the_banner_is_a_json_line_in_json_format() {
+        let mut config = stack().config;
+        config.log_format = LogFormat::Json;
+        config.verbosity = Verbosity::Quiet;
+        let mut output = Vec::new();
+
+        run_until(MockDocker::new(vec![]), &mut output, config, NoReload, || false).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let banner: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(banner, serde_json::json!({
+            "event": "startup",
+            "level": "INFO",
+            "version": env!("CARGO_PKG_VERSION"),
+            "network": ["network"],
+            "label": "flag",
+            "target": ["web"],
+            "dependencies": ["api"],
+        }));
+        assert!(output.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()), "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn list_prints_every_container_with_its_classification() {
+        let mut outside = raw("out0000000000000000", "out", "", false);
+        outside.networks.clear();
+        let docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+            raw("db00000000000000000", "db", "1.1.1.3", false),
+            outside,
+        ]]);
+        let mut output = Vec::new();
+
+        super::list(docker, &mut output, stack().config).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let rows: Vec<Vec<&str>> = output.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(rows, vec![
+            vec!["CONTAINER", "CLASSIFICATION", "SERVICE", "NAME", "IP"],
+            vec!["api0000000000000", "flagged", "api", "api", "1.1.1.2"],
+            vec!["out0000000000000", "ignored-network", "out", "out", "-"],
+            vec!["db00000000000000", "ignored-noflag", "db", "db", "1.1.1.3"],
+            vec!["web0000000000000", "target", "web", "web", "1.1.1.1"],
+        ]);
+    }
+
+    #[tokio::test]
+    pub async fn a_missing_network_is_warned_about_at_startup() {
+        let mut docker = MockDocker::new(vec![vec![raw("api0000000000000000", "api", "1.1.1.2", true)]]);
+        docker.missing_networks.insert("network".into());
+        let mut output = Vec::new();
+
+        run_until(docker, &mut output, stack().config, NoReload, || false).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("WARN network network does not exist, no container will be routed until it is created"), "{}", output);
+
+        let mut output = Vec::new();
+        run_until(MockDocker::new(vec![]), &mut output, stack().config, NoReload, || false).await.unwrap();
+        assert!(!String::from_utf8(output).unwrap().contains("does not exist"));
+    }
+
+    #[tokio::test]
+    pub async fn auto_network_is_picked_from_the_target() {
+        let attach = |mut raw: RawContainer, networks: &[&str]| {
+            let ip = raw.networks.remove("network").unwrap();
+            raw.networks = networks.iter().map(|network| (network.to_string(), ip.clone())).collect();
+            raw
+        };
+        let api = attach(raw("api0000000000000000", "api", "1.1.1.2", true), &["proj_default"]);
+        let web = attach(raw("web0000000000000000", "web", "1.1.1.1", false), &["bridge", "proj_default"]);
+        let mut docker = MockDocker::new(vec![vec![api.clone()], vec![api, web]]);
+        docker.networks = vec!["proj_default".into()];
+        let mut config = stack().config;
+        config.network = vec![super::AUTO_NETWORK.into()];
+        let mut stack = CurrentStack::new(config);
+        let mut output = Vec::new();
+
+        // nothing is recorded before the target shows, so api is still new once it does
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        assert!(docker.updated().is_empty());
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(stack.config.network, vec!["proj_default".to_string()]);
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+        assert!(String::from_utf8(output).unwrap().contains("NOTICE picked network proj_default, the one of target container web0000000000000"));
+    }
+
+    #[tokio::test]
+    pub async fn auto_network_is_picked_again_when_the_target_moves() {
+        let on = |raw: RawContainer, network: &str| {
+            let ip = raw.networks["network"].clone();
+            RawContainer { networks: HashMap::from([(network.to_string(), ip)]), ..raw }
+        };
+        let api = on(raw("api0000000000000000", "api", "1.1.1.2", true), "first");
+        let admin = on(raw("adm0000000000000000", "admin", "1.1.9.2", true), "second");
+        let mut docker = MockDocker::new(vec![
+            vec![api.clone(), on(raw("web0000000000000000", "web", "1.1.1.1", false), "first")],
+            vec![api, admin.clone(), on(raw("web1000000000000000", "web", "1.1.9.1", false), "second")],
+            vec![admin],
+        ]);
+        docker.networks = vec!["first".into(), "second".into()];
+        let mut config = stack().config;
+        config.network = vec![super::AUTO_NETWORK.into()];
+        let mut stack = CurrentStack::new(config);
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(stack.config.network, vec!["first".to_string()]);
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(stack.config.network, vec!["second".to_string()]);
+        assert_eq!(docker.clears.lock().unwrap().as_slice(), [("api0000000000000000".to_string(), "first".to_string())]);
+        assert_eq!(docker.updated(), vec![
+            ("api0000000000000000".to_string(), "1.1.1.1".to_string()),
+            ("adm0000000000000000".to_string(), "1.1.9.1".to_string()),
+        ]);
+
+        // a target gone keeps the network picked
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(stack.config.network, vec!["second".to_string()]);
+    }
+
+    #[test]
+    pub fn auto_network_is_not_polled_as_a_network() {
+        let mut config = stack().config;
+        config.network = vec![super::AUTO_NETWORK.into()];
+        config.require_flag = Some(false);
+
+        assert_eq!(config.poll_filters(), vec![PollFilter::Label("com.docker.compose.service=web".into())]);
+
+        config.network = vec!["proj_default".into()];
+        assert_eq!(config.poll_filters()[0], PollFilter::Network("proj_default".into()));
+    }
+
+    #[tokio::test]
+    pub async fn a_container_back_in_the_network_is_routed_again() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut outside = api.clone();
+        outside.networks.clear();
+        let mut docker = MockDocker::new(vec![vec![api.clone(), web.clone()], vec![outside, web.clone()], vec![api, web]]);
+        let mut stack = stack();
+        stack.config.cleanup_on_lost_network = true;
+        let mut output = Vec::new();
+
+        for _ in 0..3 {
+            stack.loop_once(&mut docker, &mut output).await.unwrap();
+        }
+
+        let routed = ("api0000000000000000".to_string(), "1.1.1.1".to_string());
+        assert_eq!(docker.updated(), vec![routed.clone(), routed]);
+        assert!(stack.applied.contains_key("api0000000000000000"));
+        assert!(String::from_utf8(output).unwrap().contains("event container lost network"));
+    }
+
+    #[tokio::test]
+    pub async fn debounced_containers_wait_for_a_second_poll() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut outside = raw("api0000000000000000", "api", "", true);
+        outside.networks.clear();
+        let mut docker = MockDocker::new(vec![vec![outside, web.clone()], vec![api.clone(), web.clone()], vec![api, web]]);
+        let mut stack = stack();
+        stack.config.debounce_polls = 2;
+
+        // api moves from outside the network to flagged, which starts its count over
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert!(docker.updated().is_empty());
+        assert_eq!(stack.target_ip["web"].ip, "1.1.1.1");
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+        assert!(stack.pending.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn route_into_target_writes_the_dependents_in_the_target() {
+        let mut docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false), raw("db00000000000000000", "db", "1.1.1.3", true)],
+        ]);
+        let mut stack = stack();
+        stack.config.route_into_target = true;
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(
+            docker.hosts.lock().unwrap()["web0000000000000000"],
+            format!("### open {} network web\n1.1.1.2\tapi\n### close {} network web\n", PACKAGE, PACKAGE)
+        );
+
+        // a dependent showing up later is added to the block
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(
+            docker.hosts.lock().unwrap()["web0000000000000000"],
+            format!("### open {} network web\n1.1.1.2\tapi\n1.1.1.3\tdb\n### close {} network web\n", PACKAGE, PACKAGE)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn route_into_target_reports_dependents_sharing_an_alias() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api1000000000000000", "api", "1.1.1.2", true),
+            raw("api2000000000000000", "api", "1.1.1.4", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        let mut stack = stack();
+        stack.config.route_into_target = true;
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(
+            docker.hosts.lock().unwrap()["web0000000000000000"],
+            format!("### open {} network web\n1.1.1.2\tapi\n### close {} network web\n", PACKAGE, PACKAGE)
+        );
+        assert!(String::from_utf8(output).unwrap().contains(
+            "WARN hostname api points at different ips in containers api1000000000000, api2000000000000\n"
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn polls_over_max_containers_are_skipped() {
+        let polled = vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("db00000000000000000", "db", "1.1.1.3", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+            raw("other00000000000000", "other", "1.1.1.4", false),
+        ];
+        let mut docker = MockDocker::new(vec![polled.clone(), polled]);
+        let mut stack = stack();
+        stack.config.max_containers = Some(2);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert!(docker.updated().is_empty());
+        assert!(String::from_utf8(output).unwrap().contains("ERROR polled 3 flagged and target containers, more than max_containers 2, skipping this poll"));
+
+        stack.config.max_containers = Some(3);
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+        assert_eq!(docker.updated().len(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn a_failing_container_does_not_stop_the_others() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("bad0000000000000000", "bad", "1.1.1.3", true),
+            raw("db00000000000000000", "db", "1.1.1.4", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        docker.broken.insert("bad0000000000000000".into());
+        let mut stack = stack();
+        stack.config.summary = true;
+        let mut output = Vec::new();
+
+        let report = stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(report, TickReport { new: 3, target: 1, updated: 2, errors: 1, ..TickReport::default() });
+        let mut updated = docker.updated();
+        updated.sort();
+        assert_eq!(updated, vec![
+            ("api0000000000000000".to_string(), "1.1.1.1".to_string()),
+            ("db00000000000000000".to_string(), "1.1.1.1".to_string()),
+        ]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("ERROR could not update /etc/hosts for container bad0000000000000, skipping it"));
+        assert!(output.ends_with("INFO poll complete: 3 flagged, 1 target, 0 gone, 0 ignored, 2 updates applied, 1 errors\n"));
+    }
+
+    #[tokio::test]
+    pub async fn reconcile_on_start_checks_existing_blocks_quietly() {
+        let poll = vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)];
+        let mut docker = MockDocker::new(vec![poll.clone(), poll.clone(), poll]);
+        stack().loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let mut config = stack().config;
+        config.reconcile_on_start = true;
+        let mut restarted = CurrentStack::new(config);
+        let mut output = Vec::new();
+        restarted.loop_once(&mut docker, &mut output).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(*docker.writes.lock().unwrap(), 1);
+        assert!(!output.contains("event container match"), "{}", output);
+        assert!(output.contains("INFO reconciled 1 flagged containers with their hosts files, 0 rewritten\n"), "{}", output);
+        assert!(!restarted.reconciling);
+    }
+
+    #[tokio::test]
+    pub async fn excluded_containers_are_not_routed_even_when_flagged() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("dbg0000000000000000", "debugger", "1.1.1.3", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        let mut stack = stack();
+        stack.config.exclude = vec!["debugger".into()];
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+        assert!(String::from_utf8(output).unwrap().contains("event container ignored (excluded): container dbg0000000000000"));
+    }
+
+    #[test]
+    pub fn exclude_matches_short_and_full_ids() {
+        let id = "4f2a9c1be8d7a0f3c5e6b7a8d9c0e1f2a3b4c5d6e7f8091a2b3c4d5e6f708192";
+        let mut config = stack().config;
+
+        config.exclude = vec!["4f2a9c1be8d7".into()];
+        assert!(config.excludes(id, None, None));
+        config.exclude = vec![id.into()];
+        assert!(config.excludes(id, None, None));
+        // too short to be told from a name, and not a start of the id
+        config.exclude = vec!["4f2a".into(), "5f2a9c1be8d7".into()];
+        assert!(!config.excludes(id, None, None));
+    }
+
+    #[test]
+    pub fn hash_does_not_panic_on_short_ids() {
+        let events = stack().actualize(HashMap::from([("abcd".to_string(), raw("abcd", "api", "1.1.1.2", true))]));
+
+        let [super::StackEvents::New(container)] = events.as_slice() else { panic!("expected a new container") };
+        assert_eq!(container.hash(), "abcd");
+        assert!(container.to_string().starts_with("container abcd flagged"));
+    }
+
+    #[tokio::test]
+    pub async fn the_newest_target_wins() {
+        let created = |mut raw: RawContainer, at: i64| {
+            raw.created = Some(at);
+            raw
+        };
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let old = created(raw("old0000000000000000", "web", "1.1.1.1", false), 100);
+        let new = created(raw("new0000000000000000", "web", "1.1.1.9", false), 200);
+        let mut docker = MockDocker::new(vec![vec![api, new, old]]);
+        let mut stack = stack();
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        assert_eq!(stack.target_ip["web"].ip, "1.1.1.9");
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.9".to_string())]);
     }
-}
 
+    #[test]
+    pub fn classify_sorts_out_new_containers() {
+        let stack = stack();
+        let classify = |raw: &RawContainer| classify(raw, &stack.config, &stack.targets);
+        let mut outside_flagged = raw("api0000000000000000", "api", "", true);
+        outside_flagged.networks.clear();
+
+        assert_eq!(classify(&raw("web0000000000000000", "web", "1.1.1.1", false)), Classification::Target);
+        assert_eq!(classify(&raw("web0000000000000000", "web", "1.1.1.1", true)), Classification::Target);
+        assert_eq!(classify(&raw("api0000000000000000", "api", "1.1.1.2", true)), Classification::Flagged);
+        assert_eq!(classify(&raw("api0000000000000000", "api", "1.1.1.2", false)), Classification::NoFlag);
+        assert_eq!(classify(&raw("api0000000000000000", "api", "", true)), Classification::Outside);
+        assert_eq!(classify(&outside_flagged), Classification::Outside);
+        assert_eq!(classify(&raw("web0000000000000000", "web", "", false)), Classification::Outside);
+    }
+
+    #[test]
+    pub fn classify_puts_exclusion_first() {
+        let mut stack = stack();
+        stack.config.exclude = vec!["web".into(), "api".into()];
+
+        assert_eq!(classify(&raw("web0000000000000000", "web", "1.1.1.1", false), &stack.config, &stack.targets), Classification::Excluded);
+        assert_eq!(classify(&raw("api0000000000000000", "api", "1.1.1.2", true), &stack.config, &stack.targets), Classification::Excluded);
+    }
+
+    #[tokio::test]
+    pub async fn a_few_failed_polls_are_tolerated() {
+        let docker = MockDocker::new(vec![
+            vec![raw("api0000000000000000", "api", "1.1.1.2", true), raw("web0000000000000000", "web", "1.1.1.1", false)],
+        ]);
+        *docker.failing_polls.lock().unwrap() = 2;
+        let mut config = stack().config;
+        config.poll_interval_ms = Some(1);
+        config.oneshot = true;
+        let mut output = Vec::new();
+
+        event_loop(docker, &mut output, config).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("WARN daemon unavailable, tolerating it (2/3 in a row)"), "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn too_many_failed_polls_stop_the_loop() {
+        let docker = MockDocker::new(vec![]);
+        *docker.failing_polls.lock().unwrap() = 2;
+        let mut config = stack().config;
+        config.poll_interval_ms = Some(1);
+        config.max_poll_errors = Some(1);
+
+        let error = event_loop(docker, Vec::new(), config).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "daemon unavailable");
+    }
+
+    #[tokio::test]
+    pub async fn target_alias_is_written_in_the_guard_block() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        let mut stack = stack();
+        stack.config.target_alias = Some("proxy.local".into());
+        stack.config.dependency_suffix = Some(".test".into());
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let hosts = docker.hosts.lock().unwrap();
+        let file = &hosts["api0000000000000000"];
+        let block = &file[file.find(" open ").unwrap()..file.find(" close ").unwrap()];
+        assert!(block.contains("1.1.1.1\tproxy.local\n"), "{}", file);
+        assert!(block.contains("1.1.1.1\tapi.test\n"), "{}", file);
+    }
+
+    #[tokio::test]
+    pub async fn state_lists_targets_and_what_was_applied() {
+        let mut docker = MockDocker::new(vec![vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("web0000000000000000", "web", "1.1.1.1", false),
+        ]]);
+        let mut stack = stack();
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        let state = stack.state();
+
+        assert_eq!(state["targets"]["web"]["ip"], "1.1.1.1");
+        assert_eq!(state["containers"][0]["container"], "api0000000000000");
+        assert_eq!(state["containers"][0]["applied"]["web"]["ip"], "1.1.1.1");
+        assert_eq!(state["containers"][0]["applied"]["web"]["hostnames"], serde_json::json!(["api"]));
+
+        let mut output = Vec::new();
+        stack.dump_state(&mut output).unwrap();
+        assert!(String::from_utf8(output).unwrap().starts_with("NOTICE state: {"));
+    }
+
+    #[tokio::test]
+    pub async fn target_is_matched_by_container_name() {
+        let mut proxy = raw("prx0000000000000000", "proxy", "1.1.1.1", false);
+        proxy.labels.clear();
+        proxy.name = Some("myproxy".into());
+        let mut docker = MockDocker::new(vec![vec![raw("api0000000000000000", "api", "1.1.1.2", true), proxy]]);
+        let mut stack = stack();
+        stack.config.target = vec!["myproxy".into()];
+        stack.config.target_match = TargetMatch::Name;
+        stack.targets = stack.config.targets().unwrap();
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        assert_eq!(stack.config.poll_filters(), vec![PollFilter::Label("flag".into()), PollFilter::Name("myproxy".into())]);
+        assert_eq!(stack.target_ip["myproxy"].ip, "1.1.1.1");
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[tokio::test]
+    pub async fn target_is_matched_by_an_arbitrary_label() {
+        let mut proxy = raw("prx0000000000000000", "proxy", "1.1.1.1", false);
+        proxy.labels.insert("role".into(), "edge".into());
+        let mut docker = MockDocker::new(vec![vec![raw("api0000000000000000", "api", "1.1.1.2", true), proxy]]);
+        let mut stack = stack();
+        stack.config.target = vec!["edge".into()];
+        stack.config.target_match = TargetMatch::Label("role".into());
+        stack.targets = stack.config.targets().unwrap();
+
+        stack.loop_once(&mut docker, &mut Vec::new()).await.unwrap();
+
+        assert_eq!(stack.config.poll_filters()[1], PollFilter::Label("role=edge".into()));
+        assert_eq!(docker.updated(), vec![("api0000000000000000".to_string(), "1.1.1.1".to_string())]);
+    }
+
+    #[test]
+    pub fn target_match_reads_from_toml() {
+        let read = |line: &str| toml::from_str::<Config>(&format!("network = \"n\"\nlabel_key = \"flag\"\ntarget = \"t\"\n{}", line)).unwrap().target_match;
+
+        assert_eq!(read(""), TargetMatch::Service);
+        assert_eq!(read("target_match = \"name\""), TargetMatch::Name);
+        assert_eq!(read("target_match = { label = \"role\" }"), TargetMatch::Label("role".into()));
+        assert_eq!(read("target_match = \"label:role\""), TargetMatch::Label("role".into()));
+    }
+
+    #[test]
+    pub fn label_target_match_is_printed_and_read_back() {
+        let mut config = stack().config;
+        config.target_match = TargetMatch::Label("role".into());
+
+        let printed = toml::to_string(&config.resolved()).unwrap();
+
+        assert!(printed.contains("target_match = \"label:role\"\n"), "{}", printed);
+        assert_eq!(toml::from_str::<Config>(&printed).unwrap().target_match, TargetMatch::Label("role".into()));
+    }
+
+    #[tokio::test]
+    pub async fn a_target_routes_known_containers_concurrently() {
+        let flagged = vec![
+            raw("api0000000000000000", "api", "1.1.1.2", true),
+            raw("bad0000000000000000", "bad", "1.1.1.3", true),
+            raw("db00000000000000000", "db", "1.1.1.4", true),
+            raw("que0000000000000000", "queue", "1.1.1.5", true),
+        ];
+        let mut with_target = flagged.clone();
+        with_target.push(raw("web0000000000000000", "web", "1.1.1.1", false));
+        let mut docker = MockDocker::new(vec![flagged, with_target]);
+        docker.broken.insert("bad0000000000000000".into());
+        let mut stack = stack();
+        stack.config.max_concurrent_updates = Some(2);
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        let updated: Vec<_> = docker.updated().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(updated, vec!["api0000000000000000", "db00000000000000000", "que0000000000000000"]);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("ERROR could not update /etc/hosts for container bad0000000000000, skipping it"), "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn restart_on_change_clears_and_starts_over() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let proxy = raw("prx0000000000000000", "proxy", "1.1.1.9", false);
+        let docker = MockDocker::new(vec![vec![api.clone(), web.clone()], vec![api, web, proxy]]);
+        let mut config = stack().config;
+        config.poll_interval_ms = Some(1);
+        let mut reloaded = config.clone();
+        reloaded.target = vec!["proxy".into()];
+        reloaded.restart_on_change = true;
+        let source = Reload { checks: std::cell::Cell::new(0), config: Some(reloaded) };
+        let mut output = Vec::new();
+        let mut polls = 0;
+
+        run_until(docker, &mut output, config, source, || {
+            polls += 1;
+            polls < 2
+        }).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let restart = output.find("INFO config changed on disk, restarting with it\n").expect("restarted");
+        let (before, after) = output.split_at(restart);
+        assert!(before.contains("recording ip for target web"), "{}", output);
+        assert!(after.contains("cleared /etc/hosts for container api0000000000000"), "{}", output);
+        assert!(after.contains("routed via service «proxy»"), "{}", output);
+        assert!(after.contains("recording ip for target proxy: 1.1.1.9"), "{}", output);
+        assert!(!after.contains("target web"), "{}", output);
+    }
+
+    #[tokio::test]
+    pub async fn changing_network_clears_the_blocks_of_the_old_one() {
+        let on = |network: &str, mut raw: RawContainer| {
+            let ip = raw.networks.remove("network").unwrap();
+            raw.networks.insert(network.to_string(), ip);
+            raw
+        };
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let docker = MockDocker::new(vec![
+            vec![api.clone(), web.clone()],
+            vec![on("other", api), on("other", web)],
+        ]);
+        let mut config = stack().config;
+        config.poll_interval_ms = Some(1);
+        let mut reloaded = config.clone();
+        reloaded.network = vec!["other".into()];
+        let source = Reload { checks: std::cell::Cell::new(0), config: Some(reloaded) };
+        let mut output = Vec::new();
+        let mut polls = 0;
+
+        run_until(docker, &mut output, config, source, || {
+            polls += 1;
+            polls < 2
+        }).await.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let reload = output.find("config changed on disk, reloading it").expect("reloaded");
+        let cleared = output.find("cleared /etc/hosts for container api0000000000000").expect("cleared");
+        assert!(cleared < reload, "{}", output);
+        assert_eq!(output.matches("updating /etc/hosts for container api0000000000000 with target web").count(), 2, "{}", output);
+    }
+
+    #[test]
+    pub fn guards_change_with_the_network_or_the_target() {
+        let config = stack().config;
+        let mut other = config.clone();
+        assert!(config.same_guards(&other));
+
+        other.network = vec!["other".into()];
+        assert!(!config.same_guards(&other));
+
+        let mut other = config.clone();
+        other.poll_interval_ms = Some(1);
+        assert!(config.same_guards(&other));
+    }
+
+    #[tokio::test]
+    pub async fn target_leaving_forgets_its_ip() {
+        let api = raw("api0000000000000000", "api", "1.1.1.2", true);
+        let web = raw("web0000000000000000", "web", "1.1.1.1", false);
+        let mut docker = MockDocker::new(vec![vec![api.clone(), web], vec![api]]);
+        let mut stack = stack();
+        stack.config.cleanup_on_target_gone = true;
+        let mut output = Vec::new();
+
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+        assert!(stack.target_ip.contains_key("web"));
+        stack.loop_once(&mut docker, &mut output).await.unwrap();
+
+        assert!(stack.target_ip.is_empty());
+        assert_eq!(*docker.clears.lock().unwrap(), vec![("api0000000000000000".to_string(), "network".to_string())]);
+        assert!(String::from_utf8(output).unwrap().contains("WARN event target gone: container web0000000000000"));
+    }
+}
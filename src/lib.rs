@@ -0,0 +1,12 @@
+#![deny(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![allow(clippy::future_not_send)]
+
+//! Keeps the `/etc/hosts` of flagged docker containers pointing their dependencies at a target service.
+//!
+//! The hosts file editing is free of any docker machinery and can be used on its own.
+
+pub mod business;
+
+pub use business::{clear_host_file, trim_host_from_guards, update_host_file, Config, Container, Docker, Guard};
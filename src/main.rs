@@ -3,122 +3,591 @@
 #![warn(clippy::nursery)]
 #![allow(clippy::future_not_send)]
 
-mod business;
-use futures_util::stream::TryStreamExt;
+#[cfg(feature = "syslog")]
+mod syslog;
+use futures_util::stream::{Stream, StreamExt, TryStreamExt};
 
-use crate::business::{event_loop, Config, RawContainer, DockerError};
+use local_stack_focus::business::{self, event_loop_with_source, Config, LogWrite, ConfigSource, HostsUpdate, PollFilter, RawContainer, DockerError, Verbosity};
 use async_trait::async_trait;
-use bollard::container::{DownloadFromContainerOptions, ListContainersOptions};
-use bollard::models::ContainerSummary;
-use bollard::Docker;
-use std::collections::HashMap;
-use std::fs;
-use std::io::{Read, stdout};
-use std::process::Command;
-use bollard::exec::{CreateExecOptions, StartExecOptions};
-
-impl From<ContainerSummary> for RawContainer {
-    fn from(summary: ContainerSummary) -> Self {
-        let networks: HashMap<String, String> =
-            summary
-                .network_settings
-                .map_or_else(HashMap::new, |settings| {
-                    settings.networks.map_or_else(HashMap::new, |map| {
-                        map.iter()
-                            .flat_map(|(key, val)| {
-                                if let Some(ip) = &val.ip_address {
-                                    Some((key.clone(), ip.clone()))
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect()
-                    })
-                });
-
-        let name = summary
-            .names
-            .unwrap_or_default()
-            .get(0)
-            .and_then(|name| name.strip_prefix('/').map(ToString::to_string));
-
-        Self {
-            id: summary.id.expect("containers must have an id"),
-            name,
-            networks,
-            labels: summary.labels.unwrap_or_default(),
-        }
+use clap::Parser;
+use bollard::container::{DownloadFromContainerOptions, ListContainersOptions, UploadToContainerOptions};
+use bollard::models::{ContainerInspectResponse, ContainerSummary, EndpointSettings, EventMessage, EventMessageTypeEnum};
+use bollard::errors::Error as BollardError;
+use bollard::{Docker, API_DEFAULT_VERSION};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, stdin, stdout};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::container::LogOutput;
+use bollard::system::EventsOptions;
+use bollard::network::ListNetworksOptions;
+
+/// Splits the endpoints of a container into its ipv4 and ipv6 address per network.
+///
+/// An attached network whose ipv4 is not assigned yet is kept with an empty address.
+fn addresses(networks: Option<&HashMap<String, EndpointSettings>>) -> (HashMap<String, String>, HashMap<String, String>) {
+    let networks = networks.into_iter().flatten();
+
+    (
+        networks.clone().filter_map(|(key, val)| val.ip_address.clone().map(|ip| (key.clone(), ip))).collect(),
+        networks.filter_map(|(key, val)| val.global_ipv6_address.clone().filter(|ip| !ip.is_empty()).map(|ip| (key.clone(), ip))).collect(),
+    )
+}
+
+/// Keeps what the business side needs out of a listed container.
+fn raw_container(summary: ContainerSummary) -> RawContainer {
+    let (networks, ipv6_networks) = addresses(summary.network_settings.as_ref().and_then(|settings| settings.networks.as_ref()));
+
+    let name = summary
+        .names
+        .unwrap_or_default()
+        .first()
+        .and_then(|name| name.strip_prefix('/').map(ToString::to_string));
+
+    RawContainer {
+        id: summary.id.expect("containers must have an id"),
+        name,
+        networks,
+        ipv6_networks,
+        labels: summary.labels.unwrap_or_default(),
+        created: summary.created,
+    }
+}
+
+/// Same as `raw_container`, out of an inspected container.
+fn inspected_container(inspect: ContainerInspectResponse) -> RawContainer {
+    let (networks, ipv6_networks) = addresses(inspect.network_settings.as_ref().and_then(|settings| settings.networks.as_ref()));
+
+    RawContainer {
+        id: inspect.id.expect("containers must have an id"),
+        name: inspect.name.map(|name| name.strip_prefix('/').map_or_else(|| name.clone(), ToString::to_string)),
+        networks,
+        ipv6_networks,
+        labels: inspect.config.and_then(|config| config.labels).unwrap_or_default(),
+        created: inspect.created.as_deref().and_then(|created| humantime::parse_rfc3339(created).ok()).and_then(|created| {
+            created.duration_since(SystemTime::UNIX_EPOCH).ok().and_then(|since| i64::try_from(since.as_secs()).ok())
+        }),
     }
 }
 
+type Events = Pin<Box<dyn Stream<Item = Result<EventMessage, BollardError>> + Send>>;
+
+/// What the event stream taught us since the last poll.
+///
+/// While the stream is alive, `poll` only lists the containers it reported and patches
+/// the previous listing; without a stream every poll lists everything again.
+#[derive(Default)]
+struct Seen {
+    containers: Option<HashMap<String, RawContainer>>,
+    affected: HashSet<String>,
+}
+
 struct DockerImpl {
-    wrap: Docker
+    wrap: Docker,
+    socket: Option<String>,
+    connection: String,
+    hosts_path: PathBuf,
+    exec_timeout: Duration,
+    events: Mutex<Option<Events>>,
+    /// Why the event stream was dropped, until the loop logs it.
+    events_lost: Option<String>,
+    seen: Mutex<Seen>,
 }
 
 impl DockerImpl {
-    pub fn new() -> business::Result<Self> {
+    pub fn new(config: &Config) -> business::Result<Self> {
+        let socket = config.docker_socket.clone().or_else(|| std::env::var("DOCKER_SOCKET").ok());
+        let (wrap, connection) = Self::connect(socket.as_deref())?;
+        let events = config.events().then(|| Self::subscribe(&wrap));
+
         Ok(Self {
-            wrap: Docker::connect_with_unix_defaults()?
+            wrap,
+            socket,
+            connection,
+            hosts_path: PathBuf::from(config.hosts_path()),
+            exec_timeout: config.exec_timeout(),
+            events: Mutex::new(events),
+            events_lost: None,
+            seen: Mutex::default(),
         })
     }
+
+    fn subscribe(docker: &Docker) -> Events {
+        let opts = EventsOptions::<&str> {
+            filters: HashMap::from([("type", vec!["container", "network"])]),
+            ..Default::default()
+        };
+
+        Box::pin(docker.events(Some(opts)))
+    }
+
+    fn affected_container(event: &EventMessage) -> Option<String> {
+        let actor = event.actor.as_ref()?;
+        match event.typ {
+            Some(EventMessageTypeEnum::NETWORK) => actor.attributes.as_ref()?.get("container").cloned(),
+            _ => actor.id.clone(),
+        }
+    }
+
+    /// The client along with how it reaches the daemon, for the loop to log.
+    fn connect(socket: Option<&str>) -> business::Result<(Docker, String)> {
+        let connected = match std::env::var("DOCKER_HOST") {
+            Ok(host) if tls_verify() && (host.starts_with("tcp://") || host.starts_with("https://")) => {
+                (Self::connect_with_ssl(&host)?, format!("docker daemon over https at {}", host))
+            }
+            Ok(host) if host.starts_with("tcp://") || host.starts_with("http://") => {
+                (Docker::connect_with_http_defaults()?, format!("docker daemon over http at {}", host))
+            }
+            _ => match socket {
+                Some(socket) => {
+                    (Docker::connect_with_unix(socket, 120, API_DEFAULT_VERSION)?, format!("docker daemon over unix socket {}", socket))
+                }
+                None => match podman_socket() {
+                    Some(socket) => (
+                        Docker::connect_with_unix(&socket.to_string_lossy(), 120, API_DEFAULT_VERSION)?,
+                        format!("podman over unix socket {}, docker socket {} is missing", socket.display(), DEFAULT_SOCKET),
+                    ),
+                    None => (Docker::connect_with_unix_defaults()?, format!("docker daemon over unix socket {}", DEFAULT_SOCKET)),
+                },
+            },
+        };
+
+        Ok(connected)
+    }
+
+    fn connect_with_ssl(host: &str) -> business::Result<Docker> {
+        let cert_path = std::env::var("DOCKER_CERT_PATH").map_err(|_| DockerError::NoCertPath)?;
+        let cert_path = Path::new(&cert_path);
+        let [ca, cert, key] = ["ca.pem", "cert.pem", "key.pem"].map(|file| cert_path.join(file));
+
+        for file in [&ca, &cert, &key] {
+            if !file.is_file() {
+                return Err(DockerError::MissingCertificate(file.display().to_string()).into());
+            }
+        }
+
+        Ok(Docker::connect_with_ssl(host, &key, &cert, &ca, 120, API_DEFAULT_VERSION)?)
+    }
+}
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// Where podman serves its docker compatible api: rootless for the current user first, then rootful.
+fn podman_sockets(runtime_dir: Option<String>, uid: Option<u32>) -> Vec<PathBuf> {
+    let mut sockets: Vec<_> = runtime_dir
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(uid.map(|uid| PathBuf::from(format!("/run/user/{}", uid))))
+        .chain(std::iter::once(PathBuf::from("/run")))
+        .map(|dir| dir.join("podman/podman.sock"))
+        .collect();
+    sockets.dedup();
+    sockets
+}
+
+/// The podman socket to fall back on when the docker one is missing.
+fn podman_socket() -> Option<PathBuf> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    if Path::new(DEFAULT_SOCKET).exists() {
+        return None;
+    }
+
+    let uid = fs::metadata("/proc/self").map(|metadata| metadata.uid()).ok();
+    podman_sockets(std::env::var("XDG_RUNTIME_DIR").ok(), uid)
+        .into_iter()
+        .find(|socket| fs::metadata(socket).is_ok_and(|metadata| metadata.file_type().is_socket()))
+}
+
+fn list_options<'a>(filter: &'a PollFilter, ids: &[&'a str]) -> ListContainersOptions<&'a str> {
+    let mut filters = HashMap::from([(filter.key(), vec![filter.value()])]);
+    if !ids.is_empty() {
+        filters.insert("id", ids.to_vec());
+    }
+
+    ListContainersOptions { filters, ..Default::default() }
+}
+
+fn download_options(hosts_path: &Path) -> DownloadFromContainerOptions<String> {
+    DownloadFromContainerOptions { path: hosts_path.to_string_lossy().into_owned() }
+}
+
+/// Archives are extracted in the directory holding the hosts file.
+fn upload_options(hosts_path: &Path) -> UploadToContainerOptions<String> {
+    let directory = hosts_path.parent().unwrap_or_else(|| Path::new("/"));
+    UploadToContainerOptions { path: directory.to_string_lossy().into_owned(), ..Default::default() }
+}
+
+/// How the daemon words a refused upload over a file that is a mount point.
+fn is_busy_mount(message: &str) -> bool {
+    message.contains("device or resource busy")
+}
+
+fn tls_verify() -> bool {
+    std::env::var("DOCKER_TLS_VERIFY").as_deref() == Ok("1")
+}
+
+fn docker_error(error: BollardError) -> business::Error {
+    match error {
+        BollardError::HyperResponseError { .. } | BollardError::IOError { .. } | BollardError::RequestTimeoutError => {
+            DockerError::Connection(error.to_string()).into()
+        }
+        BollardError::DockerResponseServerError { status_code: 404, message } => {
+            DockerError::NoSuchContainer(message).into()
+        }
+        BollardError::DockerResponseServerError { status_code, .. } if status_code >= 500 => {
+            DockerError::Transient(error.to_string()).into()
+        }
+        error => error.into(),
+    }
 }
 
 #[async_trait]
 impl business::Docker for DockerImpl {
-    async fn poll(&mut self) -> business::Result<HashMap<String, RawContainer>> {
-        let docker = &self.wrap;
-
-        let opts = Some(ListContainersOptions::<&str>::default());
-        let list = docker.list_containers(opts).await?;
-        Ok(list
-            .into_iter()
-            .map(|container| {
-                let raw = RawContainer::from(container);
+    async fn poll(&self, filters: &[PollFilter]) -> business::Result<HashMap<String, RawContainer>> {
+        let (previous, affected) = {
+            let mut seen = self.seen.lock().expect("poisoned");
+            let previous = seen.containers.take().filter(|_| self.events.lock().expect("poisoned").is_some());
+            (previous, std::mem::take(&mut seen.affected))
+        };
+
+        let containers = match previous {
+            Some(containers) if affected.is_empty() => containers,
+            Some(mut containers) => {
+                for id in &affected {
+                    containers.remove(id);
+                }
+                let ids = affected.iter().map(String::as_str).collect::<Vec<_>>();
+                containers.extend(self.list(filters, &ids).await?);
+                containers
+            }
+            None => self.list(filters, &[]).await?,
+        };
+
+        self.seen.lock().expect("poisoned").containers = Some(containers.clone());
+        Ok(containers)
+    }
+
+    async fn inspect(&self, id: &str) -> business::Result<Option<RawContainer>> {
+        match self.wrap.inspect_container(id, None).await {
+            Ok(inspect) => Ok(Some(inspected_container(inspect))),
+            Err(BollardError::DockerResponseServerError { status_code: 404, .. }) => Ok(None),
+            Err(e) => Err(docker_error(e)),
+        }
+    }
+
+    async fn network_exists(&self, name: &str) -> business::Result<bool> {
+        let options = ListNetworksOptions { filters: HashMap::from([("name", vec![name])]) };
+        let networks = self.wrap.list_networks(Some(options)).await.map_err(docker_error)?;
+
+        // the name filter also matches on part of the name
+        Ok(networks.iter().any(|network| network.name.as_deref() == Some(name)))
+    }
+
+    async fn list_networks(&self) -> business::Result<Vec<String>> {
+        let options = ListNetworksOptions { filters: HashMap::from([("type", vec!["custom"])]) };
+        let networks = self.wrap.list_networks(Some(options)).await.map_err(docker_error)?;
+
+        Ok(networks.into_iter().filter_map(|network| network.name).collect())
+    }
+
+    fn connection(&self) -> Option<String> {
+        Some(self.connection.clone())
+    }
+
+    async fn reconnect(&mut self) -> business::Result<()> {
+        (self.wrap, _) = Self::connect(self.socket.as_deref())?;
+        let events = self.events.get_mut().expect("poisoned");
+        if events.is_some() {
+            *events = Some(Self::subscribe(&self.wrap));
+        }
+        self.seen.get_mut().expect("poisoned").containers = None;
+        Ok(())
+    }
+
+    async fn wait(&mut self, timeout: Duration) -> business::Result<()> {
+        let Some(events) = self.events.get_mut().expect("poisoned") else {
+            tokio::time::sleep(timeout).await;
+            return Ok(());
+        };
+
+        match tokio::time::timeout(timeout, events.next()).await {
+            Err(_) => {}
+            Ok(Some(Ok(event))) => {
+                let seen = self.seen.get_mut().expect("poisoned");
+                seen.affected.extend(Self::affected_container(&event));
+            }
+            Ok(Some(Err(e))) => {
+                self.events_lost = Some(format!("docker event stream failed: {}", e));
+                *self.events.get_mut().expect("poisoned") = None;
+            }
+            Ok(None) => {
+                self.events_lost = Some("docker event stream closed".to_string());
+                *self.events.get_mut().expect("poisoned") = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_events_lost(&mut self) -> Option<String> {
+        self.events_lost.take()
+    }
+
+    async fn update_hosts_for(&self, container: business::Container, dependencies: &[String], guard: &business::Guard<'_>, host: &business::Address) -> business::Result<HostsUpdate> {
+        let (buffer, header) = self.read_hosts(&container).await?;
+        let new_host_file = business::update_host_file(buffer.clone(), dependencies, guard, &host.ip, host.ipv6.as_deref());
+        let update = business::hosts_update(&buffer, &new_host_file);
+        if update != HostsUpdate::Unchanged {
+            self.write_hosts(&container, &header, &new_host_file).await?;
+        }
+        Ok(update)
+    }
+
+    async fn clear_hosts_for(&self, container: business::Container, guard: &business::Guard<'_>) -> business::Result<()> {
+        let (buffer, header) = self.read_hosts(&container).await?;
+        let new_host_file = business::clear_host_file(buffer, guard);
+        self.write_hosts(&container, &header, &new_host_file).await
+    }
+}
+
+impl DockerImpl {
+    /// The daemon ands label filters together, so each label needs its own listing.
+    async fn list(&self, filters: &[PollFilter], ids: &[&str]) -> business::Result<HashMap<String, RawContainer>> {
+        let mut containers = HashMap::new();
+        for filter in filters {
+            let list = self.wrap.list_containers(Some(list_options(filter, ids))).await.map_err(docker_error)?;
+            containers.extend(list.into_iter().map(|container| {
+                let raw = raw_container(container);
                 (raw.id.clone(), raw)
-            })
-            .collect::<HashMap<String, RawContainer>>())
+            }));
+        }
+        Ok(containers)
     }
 
-    async fn update_hosts_for(&self, container: business::Container, dependencies: &[String], network: &str, target: &str, host: &str) -> business::Result<()> {
+    async fn read_hosts(&self, container: &business::Container) -> business::Result<(String, tar::Header)> {
         let name = container.name().ok_or(DockerError::NoName(container.id()))?;
-        let opts = Some(DownloadFromContainerOptions{path: "/etc/hosts", ..Default::default()});
-        let res = self.wrap.download_from_container(&name, opts);
+        let res = self.wrap.download_from_container(&name, Some(download_options(&self.hosts_path)));
 
         let bytes = res.try_fold(Vec::new(), |mut acc, chunk| async move {
             acc.extend_from_slice(&chunk[..]);
             Ok(acc)
-        }).await?;
-
-        let mut a: tar::Archive<&[u8]> = tar::Archive::new(&bytes[..]);
-        let mut buffer = String::new();
-        let _ = a.entries()
-            .or_else(|_| Err(DockerError::NoHost(container.id())))?
-            .nth(0).ok_or_else(|| DockerError::NoHost(container.id()))??
-            .read_to_string(&mut buffer)?
-            ;
-        let buffer = buffer.replace("\\t", "\t").replace("\\n", "\n").to_string();
-        let new_host_file = business::update_host_file(buffer, dependencies, network, target, host);
-
-        Command::new("docker")
-            .args(&["exec", "-u", "root", &container.id(), "sh", "-c", &format!(r#"echo "{}" > /etc/hosts"#, new_host_file)])
-            .output()?;
+        }).await.map_err(docker_error)?;
 
-        Ok(())
+        let file_name = self.hosts_path.file_name().and_then(|name| name.to_str()).unwrap_or("hosts");
+        Ok(business::read_hosts_archive(&bytes, file_name)?.ok_or_else(|| DockerError::NoHost(container.id()))?)
+    }
+
+    async fn write_hosts(&self, container: &business::Container, header: &tar::Header, new_host_file: &str) -> business::Result<()> {
+        let archive = business::hosts_archive(header, new_host_file)?;
+        let opts = Some(upload_options(&self.hosts_path));
+
+        match self.wrap.upload_to_container(&container.id(), opts, archive.into()).await {
+            Ok(()) => Ok(()),
+            // a bind mounted hosts file cannot be replaced by extracting an archive over it
+            Err(BollardError::DockerResponseServerError { message, .. }) if is_busy_mount(&message) => self.exec_hosts(container, new_host_file).await,
+            Err(e) => Err(docker_error(e)),
+        }
+    }
+
+    /// A stuck exec would hold the whole loop, so it is bounded by `exec_timeout_ms`.
+    async fn exec_hosts(&self, container: &business::Container, new_host_file: &str) -> business::Result<()> {
+        tokio::time::timeout(self.exec_timeout, self.run_exec(container, new_host_file))
+            .await
+            .map_err(|_| DockerError::ExecTimeout(container.id()))?
     }
+
+    async fn run_exec(&self, container: &business::Container, new_host_file: &str) -> business::Result<()> {
+        let script = format!(
+            "printf '%s' {} > {}",
+            business::shell_quote(new_host_file),
+            business::shell_quote(&self.hosts_path.to_string_lossy())
+        );
+        let opts = CreateExecOptions {
+            cmd: Some(vec!["sh", "-c", &script]),
+            user: Some("root"),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            ..Default::default()
+        };
+        let exec = self.wrap.create_exec(&container.id(), opts).await.map_err(docker_error)?;
+
+        let started = self.wrap.start_exec(&exec.id, Some(StartExecOptions::default())).await.map_err(docker_error)?;
+
+        let mut stderr = String::new();
+        if let StartExecResults::Attached { mut output, .. } = started {
+            while let Some(chunk) = output.try_next().await.map_err(docker_error)? {
+                if let LogOutput::StdErr { message } = chunk {
+                    stderr.push_str(&String::from_utf8_lossy(&message));
+                }
+            }
+        }
+
+        let inspected = self.wrap.inspect_exec(&exec.id).await.map_err(docker_error)?;
+        // without an exit code there is no telling the file was written
+        match inspected.exit_code {
+            Some(0) => Ok(()),
+            Some(_) => Err(DockerError::ExecFailed(container.id(), stderr.trim_end().to_string()).into()),
+            None => Err(DockerError::ExecFailed(container.id(), "no exit code reported".to_string()).into()),
+        }
+    }
+}
+
+/// Reads a `.json` config as json, anything else as toml.
+fn config(config_file: &str) -> business::Result<Config> {
+    let content = fs::read_to_string(config_file)?;
+    if Path::new(config_file).extension().is_some_and(|extension| extension == "json") {
+        return validated(serde_json::from_str::<Config>(&content)?);
+    }
+    parse_config(&content)
 }
 
-fn config() -> business::Result<Config> {
-    let config_file = std::env::var("LOCAL_STACK_FOCUS")
-        .unwrap_or_else(|_| String::from("/local_stack_focus.toml"));
+fn parse_config(content: &str) -> business::Result<Config> {
+    validated(toml::from_str::<Config>(content)?)
+}
 
-    let config = fs::read_to_string(config_file)?;
-    let config = toml::from_str::<Config>(&config)?;
+fn validated(mut config: Config) -> business::Result<Config> {
+    config.expand_env()?;
+    config.validate()?;
     Ok(config)
 }
 
+struct ConfigFile {
+    path: String,
+    /// The config given as is, read from stdin when the path is `-` or from LOCAL_STACK_FOCUS_INLINE; it never changes.
+    inline: Option<String>,
+    modified: Option<SystemTime>,
+    args: Args,
+}
+
+impl ConfigFile {
+    pub fn new(args: Args) -> business::Result<Self> {
+        let (path, inline) = match (args.config.clone(), std::env::var("LOCAL_STACK_FOCUS_INLINE")) {
+            (Some(path), _) => (path, None),
+            (None, Ok(content)) => (String::from("LOCAL_STACK_FOCUS_INLINE"), Some(content)),
+            (None, Err(_)) => {
+                let path = std::env::var("LOCAL_STACK_FOCUS").unwrap_or_else(|_| String::from("/local_stack_focus.toml"));
+                (path, None)
+            }
+        };
+        let inline = match inline {
+            None if path == "-" => {
+                let mut content = String::new();
+                stdin().read_to_string(&mut content)?;
+                Some(content)
+            }
+            inline => inline,
+        };
+
+        Ok(Self {
+            path,
+            inline,
+            modified: None,
+            args,
+        })
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()
+    }
+}
+
+impl ConfigSource for ConfigFile {
+    fn load(&mut self) -> business::Result<Config> {
+        self.modified = self.modified();
+        let mut config = match &self.inline {
+            Some(content) => parse_config(content)?,
+            None => config(&self.path)?,
+        };
+        if let Some(verbosity) = std::env::var("LOCAL_STACK_FOCUS_LOG").ok().as_deref().and_then(Verbosity::parse) {
+            config.verbosity = verbosity;
+        }
+        self.args.apply(&mut config);
+        Ok(config)
+    }
+
+    fn changed(&self) -> bool {
+        self.inline.is_none() && self.modified() != self.modified
+    }
+}
+
+fn print_config(source: &ConfigFile, mut config: Config) -> business::Result<()> {
+    config.docker_socket = config.docker_socket.or_else(|| std::env::var("DOCKER_SOCKET").ok());
+    println!("# loaded from {}", source.path);
+    print!("{}", toml::to_string(&config.resolved()).map_err(|e| e.to_string())?);
+    Ok(())
+}
+
+/// Keeps the containers of a docker network routed to a target service through their /etc/hosts.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Config file to read, `-` for stdin, overriding LOCAL_STACK_FOCUS and LOCAL_STACK_FOCUS_INLINE
+    #[arg(long)]
+    config: Option<String>,
+    /// Apply hosts once and exit, failing when no target is found
+    #[arg(long)]
+    once: bool,
+    /// Log the guard blocks instead of writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Log every poll, overriding LOCAL_STACK_FOCUS_LOG and the config verbosity
+    #[arg(long)]
+    verbose: bool,
+    /// Print the resolved config and exit without connecting to docker
+    #[arg(long)]
+    print_config: bool,
+    /// Start over when the config file changes instead of reloading it in place
+    #[arg(long)]
+    watch_config: bool,
+    /// Print how each container is classified and exit without writing anything
+    #[arg(long)]
+    list: bool,
+}
+
+impl Args {
+    fn apply(&self, config: &mut Config) {
+        config.oneshot |= self.once;
+        config.dry_run |= self.dry_run;
+        config.restart_on_change |= self.watch_config;
+        if self.verbose {
+            config.verbosity = Verbosity::Verbose;
+        }
+    }
+}
+
+fn log_writer(config: &Config) -> business::Result<Box<dyn LogWrite>> {
+    // without the feature, the loop warns about syslog through the writer falling back below, see `Config::warnings`
+    #[cfg(feature = "syslog")]
+    if config.syslog {
+        return Ok(Box::new(syslog::Syslog::connect()?));
+    }
+
+    match &config.log_file {
+        Some(path) => Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => Ok(Box::new(stdout())),
+    }
+}
+
 async fn wrap() -> business::Result<()> {
-    let config = config()?;
-    event_loop(DockerImpl::new()?, stdout(), config).await
+    let args = Args::parse();
+    let print = args.print_config;
+    let list = args.list;
+    let mut source = ConfigFile::new(args)?;
+    let config = source.load()?;
+    if print {
+        return print_config(&source, config);
+    }
+    let docker = DockerImpl::new(&config)?;
+    if list {
+        return business::list(docker, stdout(), config).await;
+    }
+    let write = log_writer(&config)?;
+    event_loop_with_source(docker, write, config, source).await
 }
 
 #[tokio::main]
@@ -128,3 +597,188 @@ async fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{config, download_options, is_busy_mount, parse_config, inspected_container, list_options, podman_sockets, raw_container, upload_options, Args, ConfigFile};
+    use local_stack_focus::business::{ConfigSource, PollFilter};
+    use clap::Parser;
+    use bollard::models::{ContainerConfig, ContainerInspectResponse, ContainerSummary, ContainerSummaryNetworkSettings, EndpointSettings, NetworkSettings};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn list_options_filter_by_label() {
+        let filter = PollFilter::Label("flag".into());
+        let opts = list_options(&filter, &[]);
+
+        assert_eq!(opts.filters, HashMap::from([("label", vec!["flag"])]));
+        assert!(!opts.all);
+    }
+
+    #[test]
+    pub fn list_options_narrow_to_affected_ids() {
+        let filter = PollFilter::Label("com.docker.compose.service=web".into());
+        let opts = list_options(&filter, &["abc"]);
+
+        assert_eq!(opts.filters, HashMap::from([
+            ("label", vec!["com.docker.compose.service=web"]),
+            ("id", vec!["abc"]),
+        ]));
+    }
+
+    #[test]
+    pub fn list_options_filter_by_name() {
+        let filter = PollFilter::Name("myproxy".into());
+
+        assert_eq!(list_options(&filter, &[]).filters, HashMap::from([("name", vec!["myproxy"])]));
+    }
+
+    #[test]
+    pub fn args_parse_the_cli_flags() {
+        let args = Args::try_parse_from(["local_stack_focus", "--config", "focus.toml", "--once", "--dry-run"]).unwrap();
+
+        assert_eq!(args.config.as_deref(), Some("focus.toml"));
+        assert!(args.once);
+        assert!(args.dry_run);
+        assert!(!args.verbose);
+        assert!(!args.print_config);
+        assert!(!args.watch_config);
+        assert!(!args.list);
+        assert!(Args::try_parse_from(["local_stack_focus", "--list"]).unwrap().list);
+    }
+
+    #[test]
+    pub fn raw_container_from_summary() {
+        let summary = ContainerSummary {
+            id: Some("abc".into()),
+            names: Some(vec!["/web".into()]),
+            labels: Some(HashMap::from([("flag".to_string(), "true".to_string())])),
+            network_settings: Some(ContainerSummaryNetworkSettings {
+                networks: Some(HashMap::from([("network".to_string(), EndpointSettings {
+                    ip_address: Some("1.1.1.1".into()),
+                    global_ipv6_address: Some(String::new()),
+                    ..Default::default()
+                })])),
+            }),
+            ..Default::default()
+        };
+
+        let raw = raw_container(summary);
+
+        assert_eq!(raw.name.as_deref(), Some("web"));
+        assert_eq!(raw.networks, HashMap::from([("network".to_string(), "1.1.1.1".to_string())]));
+        assert!(raw.ipv6_networks.is_empty());
+        assert_eq!(raw.labels.get("flag").map(String::as_str), Some("true"));
+    }
+
+    #[test]
+    pub fn raw_container_from_inspect() {
+        let inspect = ContainerInspectResponse {
+            id: Some("abc".into()),
+            name: Some("/web".into()),
+            created: Some("2024-01-02T03:04:05.123456789Z".into()),
+            config: Some(ContainerConfig {
+                labels: Some(HashMap::from([("flag".to_string(), "true".to_string())])),
+                ..Default::default()
+            }),
+            network_settings: Some(NetworkSettings {
+                networks: Some(HashMap::from([("network".to_string(), EndpointSettings {
+                    ip_address: Some("1.1.1.1".into()),
+                    global_ipv6_address: Some("fd00::1".into()),
+                    ..Default::default()
+                })])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let raw = inspected_container(inspect);
+
+        assert_eq!(raw.id, "abc");
+        assert_eq!(raw.created, Some(1_704_164_645));
+        assert_eq!(raw.name.as_deref(), Some("web"));
+        assert_eq!(raw.networks, HashMap::from([("network".to_string(), "1.1.1.1".to_string())]));
+        assert_eq!(raw.ipv6_networks, HashMap::from([("network".to_string(), "fd00::1".to_string())]));
+        assert_eq!(raw.labels.get("flag").map(String::as_str), Some("true"));
+    }
+
+    #[test]
+    pub fn hosts_path_is_used_for_download_and_upload() {
+        let path = Path::new("/etc/dnsmasq.d/focus.hosts");
+
+        assert_eq!(download_options(path).path, "/etc/dnsmasq.d/focus.hosts");
+        assert_eq!(upload_options(path).path, "/etc/dnsmasq.d");
+        assert_eq!(upload_options(Path::new("/etc/hosts")).path, "/etc");
+    }
+
+    #[test]
+    pub fn inline_config_is_parsed_and_never_reloaded() {
+        let mut source = ConfigFile {
+            path: String::from("-"),
+            inline: Some(String::from("network = \"network\"\nlabel_key = \"flag\"\ntarget = \"web\"\ndependencies = [\"api\"]\n")),
+            modified: None,
+            args: Args::try_parse_from(["local_stack_focus"]).unwrap(),
+        };
+
+        let config = source.load().unwrap();
+
+        assert_eq!(config.target, vec!["web".to_string()]);
+        assert!(!source.changed());
+    }
+
+    #[test]
+    pub fn config_is_read_as_json_or_toml_by_extension() {
+        let dir = std::env::temp_dir();
+        let toml_path = dir.join(format!("lsf-config-{}.toml", std::process::id()));
+        let json_path = dir.join(format!("lsf-config-{}.json", std::process::id()));
+        std::fs::write(&toml_path, "network = \"network\"\nlabel_key = \"flag\"\ntarget = [\"web\"]\ndependencies = [\"api\"]\npoll_interval_ms = 500\n").unwrap();
+        std::fs::write(&json_path, r#"{"network": "network", "label_key": "flag", "target": ["web"], "dependencies": ["api"], "poll_interval_ms": 500}"#).unwrap();
+
+        let from_toml = config(toml_path.to_str().unwrap());
+        let from_json = config(json_path.to_str().unwrap());
+        std::fs::remove_file(&toml_path).unwrap();
+        std::fs::remove_file(&json_path).unwrap();
+
+        assert_eq!(
+            toml::to_string(&from_json.unwrap().resolved()).unwrap(),
+            toml::to_string(&from_toml.unwrap().resolved()).unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn config_expands_environment_variables() {
+        std::env::set_var("LSF_TEST_PROJECT", "shop");
+        let content = "network = \"${LSF_TEST_PROJECT}_default\"\nlabel_key = \"flag\"\ntarget = \"web\"\ndependencies = [\"api.${LSF_TEST_PROJECT}\"]\n";
+
+        let config = parse_config(content).unwrap();
+
+        assert_eq!(config.network, vec!["shop_default".to_string()]);
+        assert_eq!(config.dependencies.as_slice(), ["api.shop".to_string()]);
+        assert_eq!(
+            parse_config("network = \"${LSF_TEST_UNDEFINED}\"\nlabel_key = \"flag\"\ntarget = \"web\"\n").unwrap_err().to_string(),
+            "config references undefined environment variable LSF_TEST_UNDEFINED"
+        );
+    }
+
+    #[test]
+    pub fn podman_sockets_are_probed_rootless_first() {
+        assert_eq!(podman_sockets(Some("/run/user/1000".into()), Some(1000)), vec![
+            PathBuf::from("/run/user/1000/podman/podman.sock"),
+            PathBuf::from("/run/podman/podman.sock"),
+        ]);
+        assert_eq!(podman_sockets(Some("/tmp/runtime".into()), Some(1000)), vec![
+            PathBuf::from("/tmp/runtime/podman/podman.sock"),
+            PathBuf::from("/run/user/1000/podman/podman.sock"),
+            PathBuf::from("/run/podman/podman.sock"),
+        ]);
+        assert_eq!(podman_sockets(None, None), vec![PathBuf::from("/run/podman/podman.sock")]);
+    }
+
+    #[test]
+    pub fn only_a_busy_mount_falls_back_to_exec() {
+        assert!(is_busy_mount("Error processing tar file(exit status 1): unlinkat /etc/hosts: device or resource busy"));
+        assert!(!is_busy_mount("No such container: api"));
+        assert!(!is_busy_mount("Could not find the file /etc in container api"));
+    }
+}
@@ -0,0 +1,70 @@
+use local_stack_focus::business::{Level, LogWrite};
+use std::io::{Result, Write};
+use std::os::unix::net::UnixDatagram;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const FACILITY_USER: u8 = 1 << 3;
+
+/// Sends every logged line as its own message to the local syslog daemon, with the severity of its level.
+pub struct Syslog {
+    socket: UnixDatagram,
+    line: Vec<u8>,
+    level: Level,
+}
+
+impl Syslog {
+    pub fn connect() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+
+        Ok(Self {
+            socket,
+            line: Vec::new(),
+            level: Level::Info,
+        })
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Notice => 5,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+
+    fn send(&mut self) -> Result<()> {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        let message = format!("<{}>{}: {}", FACILITY_USER | Self::severity(self.level), env!("CARGO_PKG_NAME"), line);
+        self.line.clear();
+        self.socket.send(message.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl LogWrite for Syslog {
+    fn level(&mut self, level: Level) {
+        self.level = level;
+    }
+}
+
+impl Write for Syslog {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.send()?;
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.line.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}